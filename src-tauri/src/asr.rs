@@ -0,0 +1,71 @@
+use std::time::{Duration, Instant};
+use async_trait::async_trait;
+use serde::{Serialize, Deserialize};
+use crate::{
+  asr_bytedance::ByteDanceProvider,
+  asr_local::LocalProvider,
+  settings::Settings,
+  subtitle::SubtitleEntry,
+};
+
+// Recognizing a whole film can take the vendor a while.
+const TRANSCRIBE_TIMEOUT: Duration = Duration::from_secs(60 * 60);
+
+pub type AsrResult<T> = Result<T, Box<dyn std::error::Error + Send + Sync>>;
+
+pub enum AsrPoll {
+  Pending,
+  Done(Vec<SubtitleEntry>),
+}
+
+/// A speech recognition engine. The audio is submitted once and the returned
+/// job polled until its utterances are ready.
+#[async_trait]
+pub trait AsrProvider: Send + Sync {
+  /// Starts recognizing the audio file and returns the job to poll.
+  async fn submit(&self, audio_path: &str) -> AsrResult<String>;
+
+  async fn poll(&self, job_id: &str) -> AsrResult<AsrPoll>;
+
+  fn poll_interval(&self) -> Duration {
+    Duration::from_secs(2)
+  }
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum AsrProviderKind {
+  /// ByteDance's openspeech caption service.
+  Bytedance,
+  /// A recognizer binary on this machine, configured by `local_asr`.
+  Local,
+}
+
+impl Default for AsrProviderKind {
+  fn default() -> Self {
+    AsrProviderKind::Bytedance
+  }
+}
+
+pub fn asr_provider(settings: &Settings) -> Box<dyn AsrProvider> {
+  match settings.asr_provider {
+    AsrProviderKind::Bytedance => Box::new(ByteDanceProvider),
+    AsrProviderKind::Local => Box::new(LocalProvider::new(settings.local_asr.clone())),
+  }
+}
+
+/// Runs the audio file through the provider and waits for its utterances.
+pub async fn transcribe(provider: &dyn AsrProvider, audio_path: &str) -> AsrResult<Vec<SubtitleEntry>> {
+  let job_id = provider.submit(audio_path).await?;
+  let deadline = Instant::now() + TRANSCRIBE_TIMEOUT;
+
+  loop {
+    if let AsrPoll::Done(entries) = provider.poll(&job_id).await? {
+      return Ok(entries);
+    }
+    if Instant::now() >= deadline {
+      return Err("Speech recognition timed out".into());
+    }
+    tokio::time::sleep(provider.poll_interval()).await;
+  }
+}
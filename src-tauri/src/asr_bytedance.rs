@@ -0,0 +1,101 @@
+use url::Url;
+use reqwest::Client;
+use async_trait::async_trait;
+use lazy_static::lazy_static;
+use serde::Deserialize;
+use serde_json::{Map, Value};
+use std::{env, fs};
+use crate::{
+  asr::{AsrPoll, AsrProvider, AsrResult},
+  subtitle::SubtitleEntry,
+};
+
+// Query result code of a job that is still being processed.
+const CODE_PROCESSING: u64 = 2000;
+
+lazy_static! {
+  static ref VC_APP_ID: String = get_vc_app_var("VC_APP_ID");
+  static ref VC_APP_ACCESS_TOKEN: String = get_vc_app_var("VC_APP_ACCESS_TOKEN");
+  static ref HTTP_CLIENT: Client = Client::new();
+}
+
+#[derive(Deserialize)]
+struct UploadEndpointResult {
+  code: u64,
+  message: String,
+  id: String,
+}
+
+#[derive(Deserialize)]
+struct ResultQueryEndpointResult {
+  code: u64,
+  message: String,
+  #[serde(default)]
+  utterances: Vec<SubtitleEntry>,
+
+  #[serde(flatten)]
+  _unknown: Map<String, Value>,
+}
+
+fn get_vc_app_var(key: &str) -> String {
+  env::var(key).unwrap_or_else(|_| "".to_string())
+}
+
+/// ByteDance's openspeech caption service, authenticated with the
+/// `VC_APP_ID` and `VC_APP_ACCESS_TOKEN` environment variables.
+pub struct ByteDanceProvider;
+
+#[async_trait]
+impl AsrProvider for ByteDanceProvider {
+  async fn submit(&self, audio_path: &str) -> AsrResult<String> {
+    let buffer = fs::read(audio_path)?;
+    let full_url = {
+      let mut url = Url::parse("https://openspeech.bytedance.com/api/v1/vc/submit")?;
+      let mut builder = url.query_pairs_mut();
+      builder.append_pair("appid", &VC_APP_ID).append_pair("max_lines", "2");
+      builder.finish().to_string()
+    };
+
+    let response = HTTP_CLIENT
+      .post(&full_url)
+      .header("Content-Type", "audio/aac")
+      .header("Authorization", "Bearer; ".to_string() + &VC_APP_ACCESS_TOKEN)
+      .body(buffer)
+      .send()
+      .await?;
+
+    if !response.status().is_success() {
+      return Err("Failed to upload audio file".into());
+    }
+    let result = response.json::<UploadEndpointResult>().await?;
+    match result.code {
+      0 => Ok(result.id),
+      _ => Err(result.message.into()),
+    }
+  }
+
+  async fn poll(&self, job_id: &str) -> AsrResult<AsrPoll> {
+    let full_url = {
+      let mut url = Url::parse("https://openspeech.bytedance.com/api/v1/vc/query")?;
+      let mut builder = url.query_pairs_mut();
+      builder.append_pair("appid", &VC_APP_ID).append_pair("id", job_id);
+      builder.finish().to_string()
+    };
+
+    let response = HTTP_CLIENT
+      .get(&full_url)
+      .header("Authorization", "Bearer; ".to_string() + &VC_APP_ACCESS_TOKEN)
+      .send()
+      .await?;
+
+    if !response.status().is_success() {
+      return Err("Failed to get order result".into());
+    }
+    let result = response.json::<ResultQueryEndpointResult>().await?;
+    match result.code {
+      0 => Ok(AsrPoll::Done(result.utterances)),
+      CODE_PROCESSING => Ok(AsrPoll::Pending),
+      _ => Err(result.message.into()),
+    }
+  }
+}
@@ -0,0 +1,383 @@
+use std::{
+  collections::HashMap,
+  fs,
+  path::{Path, PathBuf},
+  process::Stdio,
+  sync::Mutex,
+  time::Duration,
+};
+use async_trait::async_trait;
+use serde::{Serialize, Deserialize};
+use tokio::process::{Child, Command};
+use crate::{
+  asr::{AsrPoll, AsrProvider, AsrResult},
+  subtitle::SubtitleEntry,
+  subtitle_convert::{parse_srt, parse_vtt},
+};
+
+// whisper.cpp only reads 16 kHz mono PCM.
+const RECOGNIZER_INPUT_NAME: &str = "asr.wav";
+
+/// Command line conventions of the recognizer binary.
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum LocalAsrFlavor {
+  /// whisper.cpp's `whisper-cli` (formerly `main`).
+  WhisperCpp,
+  /// faster-whisper front ends that take openai-whisper's options, such as
+  /// `whisper-ctranslate2` or `faster-whisper-xxl`.
+  FasterWhisper,
+}
+
+impl Default for LocalAsrFlavor {
+  fn default() -> Self {
+    LocalAsrFlavor::WhisperCpp
+  }
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum LocalAsrOutput {
+  Srt,
+  Vtt,
+  Json,
+}
+
+impl Default for LocalAsrOutput {
+  fn default() -> Self {
+    LocalAsrOutput::Srt
+  }
+}
+
+impl LocalAsrOutput {
+  fn extension(&self) -> &'static str {
+    match self {
+      LocalAsrOutput::Srt => "srt",
+      LocalAsrOutput::Vtt => "vtt",
+      LocalAsrOutput::Json => "json",
+    }
+  }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct LocalAsrSettings {
+  /// Recognizer binary, looked up on `PATH` unless it is a path.
+  pub binary: String,
+  pub flavor: LocalAsrFlavor,
+  /// Model file for whisper.cpp, model name or directory for faster-whisper.
+  /// `None` uses the recognizer's default.
+  pub model: Option<String>,
+  /// Language hint such as "en" or "zh". `None` lets the recognizer detect it.
+  pub language: Option<String>,
+  pub threads: Option<u32>,
+  pub output_format: LocalAsrOutput,
+}
+
+impl Default for LocalAsrSettings {
+  fn default() -> Self {
+    Self {
+      binary: "whisper-cli".to_string(),
+      flavor: LocalAsrFlavor::WhisperCpp,
+      model: None,
+      language: None,
+      threads: None,
+      output_format: LocalAsrOutput::Srt,
+    }
+  }
+}
+
+#[derive(Deserialize)]
+struct WhisperCppOffsets {
+  from: u64,
+  to: u64,
+}
+
+#[derive(Deserialize)]
+struct WhisperCppSegment {
+  offsets: WhisperCppOffsets,
+  text: String,
+}
+
+#[derive(Deserialize)]
+struct WhisperSegment {
+  start: f64,
+  end: f64,
+  text: String,
+}
+
+// whisper.cpp's `-oj` output times in milliseconds, openai-whisper's JSON
+// (also written by faster-whisper front ends) in seconds.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum RecognizerJson {
+  WhisperCpp { transcription: Vec<WhisperCppSegment> },
+  Whisper { segments: Vec<WhisperSegment> },
+}
+
+fn parse_output(format: LocalAsrOutput, content: &str) -> AsrResult<Vec<SubtitleEntry>> {
+  let entries = match format {
+    LocalAsrOutput::Srt | LocalAsrOutput::Vtt => {
+      // faster-whisper front ends leave the hours out of VTT timestamps.
+      let cues = if format == LocalAsrOutput::Srt { parse_srt(content) } else { parse_vtt(content) };
+      cues.into_iter()
+        .map(|cue| SubtitleEntry { text: cue.text, start_time: cue.start, end_time: cue.end })
+        .collect()
+    },
+    LocalAsrOutput::Json => match serde_json::from_str::<RecognizerJson>(content)? {
+      RecognizerJson::WhisperCpp { transcription } => transcription
+        .into_iter()
+        .map(|segment| SubtitleEntry {
+          text: segment.text.trim().to_string(),
+          start_time: segment.offsets.from,
+          end_time: segment.offsets.to,
+        })
+        .collect(),
+      RecognizerJson::Whisper { segments } => segments
+        .into_iter()
+        .map(|segment| SubtitleEntry {
+          text: segment.text.trim().to_string(),
+          start_time: (segment.start * 1000.0).round() as u64,
+          end_time: (segment.end * 1000.0).round() as u64,
+        })
+        .collect(),
+    },
+  };
+
+  Ok(entries)
+}
+
+/// Runs a recognizer binary on the entry's audio. whisper.cpp gets an
+/// `asr.wav` made from `audio.aac`, faster-whisper reads `audio.aac` itself.
+/// The binary must write its output next to its input under the same name
+/// with the `output_format` extension, e.g. `asr.srt` or `audio.json`, then
+/// exit with status 0.
+pub struct LocalProvider {
+  settings: LocalAsrSettings,
+  jobs: Mutex<HashMap<String, Child>>,
+}
+
+impl LocalProvider {
+  pub fn new(settings: LocalAsrSettings) -> Self {
+    Self { settings, jobs: Mutex::new(HashMap::new()) }
+  }
+
+  fn output_path(&self, input: &Path) -> PathBuf {
+    input.with_extension(self.settings.output_format.extension())
+  }
+
+  fn recognizer_args(&self, input: &Path) -> Vec<String> {
+    let settings = &self.settings;
+    let input_str = input.to_string_lossy().to_string();
+    let mut args = Vec::new();
+
+    match settings.flavor {
+      LocalAsrFlavor::WhisperCpp => {
+        args.extend(["-f".to_string(), input_str.clone()]);
+        if let Some(model) = &settings.model {
+          args.extend(["-m".to_string(), model.clone()]);
+        }
+        args.extend(["-l".to_string(), settings.language.clone().unwrap_or_else(|| "auto".to_string())]);
+        if let Some(threads) = settings.threads {
+          args.extend(["-t".to_string(), threads.to_string()]);
+        }
+        let output_flag = match settings.output_format {
+          LocalAsrOutput::Srt => "-osrt",
+          LocalAsrOutput::Vtt => "-ovtt",
+          LocalAsrOutput::Json => "-oj",
+        };
+        // `-of` takes the output path without its extension.
+        args.extend([output_flag.to_string(), "-of".to_string(), input.with_extension("").to_string_lossy().to_string()]);
+      },
+      LocalAsrFlavor::FasterWhisper => {
+        args.push(input_str);
+        if let Some(model) = &settings.model {
+          args.extend(["--model".to_string(), model.clone()]);
+        }
+        if let Some(language) = &settings.language {
+          args.extend(["--language".to_string(), language.clone()]);
+        }
+        if let Some(threads) = settings.threads {
+          args.extend(["--threads".to_string(), threads.to_string()]);
+        }
+        let output_dir = input.parent().map(|dir| dir.to_string_lossy().to_string()).unwrap_or_default();
+        args.extend([
+          "--output_format".to_string(), settings.output_format.extension().to_string(),
+          "--output_dir".to_string(), output_dir,
+        ]);
+      },
+    }
+
+    args
+  }
+}
+
+#[async_trait]
+impl AsrProvider for LocalProvider {
+  async fn submit(&self, audio_path: &str) -> AsrResult<String> {
+    let input = match self.settings.flavor {
+      LocalAsrFlavor::WhisperCpp => {
+        let input = Path::new(audio_path).with_file_name(RECOGNIZER_INPUT_NAME);
+        let status = Command::new("ffmpeg")
+          .args(["-y", "-i", audio_path, "-ar", "16000", "-ac", "1", "-c:a", "pcm_s16le"])
+          .arg(&input)
+          .stdout(Stdio::null())
+          .stderr(Stdio::null())
+          .status()
+          .await?;
+        if !status.success() {
+          return Err(format!("Failed to convert audio for recognition: {}", status).into());
+        }
+        input
+      },
+      LocalAsrFlavor::FasterWhisper => PathBuf::from(audio_path),
+    };
+    let _ = fs::remove_file(self.output_path(&input));
+
+    let child = Command::new(&self.settings.binary)
+      .args(self.recognizer_args(&input))
+      .stdout(Stdio::null())
+      .kill_on_drop(true)
+      .spawn()
+      .map_err(|err| format!("Failed to start {}: {}", self.settings.binary, err))?;
+
+    let job_id = input.to_string_lossy().to_string();
+    self.jobs.lock().unwrap().insert(job_id.clone(), child);
+
+    Ok(job_id)
+  }
+
+  async fn poll(&self, job_id: &str) -> AsrResult<AsrPoll> {
+    let status = {
+      let mut jobs = self.jobs.lock().unwrap();
+      let child = jobs.get_mut(job_id).ok_or("Unknown recognition job")?;
+      match child.try_wait()? {
+        Some(status) => {
+          jobs.remove(job_id);
+          status
+        },
+        None => return Ok(AsrPoll::Pending),
+      }
+    };
+
+    let input = PathBuf::from(job_id);
+    if input.file_name().map_or(false, |name| name == RECOGNIZER_INPUT_NAME) {
+      let _ = fs::remove_file(&input);
+    }
+    if !status.success() {
+      return Err(format!("{} exited with {}", self.settings.binary, status).into());
+    }
+    let output_path = self.output_path(&input);
+    let content = fs::read_to_string(&output_path)
+      .map_err(|err| format!("No recognizer output at {}: {}", output_path.display(), err))?;
+    let _ = fs::remove_file(&output_path);
+
+    let entries = parse_output(self.settings.output_format, &content)?;
+    if entries.is_empty() {
+      return Err(format!("No subtitles in {}", output_path.display()).into());
+    }
+
+    Ok(AsrPoll::Done(entries))
+  }
+
+  fn poll_interval(&self) -> Duration {
+    Duration::from_millis(500)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn timings(entries: &[SubtitleEntry]) -> Vec<(u64, u64, &str)> {
+    entries.iter().map(|entry| (entry.start_time, entry.end_time, entry.text.as_str())).collect()
+  }
+
+  #[test]
+  fn whisper_cpp_json_is_in_milliseconds() {
+    let json = r#"{
+      "systeminfo": "AVX = 1",
+      "transcription": [
+        {"timestamps": {"from": "00:00:00,000", "to": "00:00:02,500"}, "offsets": {"from": 0, "to": 2500}, "text": " Hello"},
+        {"timestamps": {"from": "01:00:00,000", "to": "01:00:01,000"}, "offsets": {"from": 3600000, "to": 3601000}, "text": " world"}
+      ]
+    }"#;
+    let entries = parse_output(LocalAsrOutput::Json, json).unwrap();
+
+    assert_eq!(timings(&entries), [(0, 2500, "Hello"), (3600000, 3601000, "world")]);
+  }
+
+  #[test]
+  fn openai_whisper_json_is_in_seconds() {
+    let json = r#"{"text": " Hi there", "segments": [{"id": 0, "start": 1.5, "end": 3.25, "text": " Hi there"}], "language": "en"}"#;
+    let entries = parse_output(LocalAsrOutput::Json, json).unwrap();
+
+    assert_eq!(timings(&entries), [(1500, 3250, "Hi there")]);
+  }
+
+  #[test]
+  fn vtt_timestamps_may_leave_out_hours() {
+    let vtt = "WEBVTT\n\n00:01.000 --> 00:02.500\nFirst\n\n59:59.000 --> 1:00:01.000\nSecond\n";
+    let entries = parse_output(LocalAsrOutput::Vtt, vtt).unwrap();
+
+    assert_eq!(timings(&entries), [(1000, 2500, "First"), (3599000, 3601000, "Second")]);
+  }
+
+  // Stands in for a faster-whisper front end: records its arguments and
+  // writes a fixed transcript in the requested format.
+  #[cfg(unix)]
+  const FAKE_RECOGNIZER: &str = r#"#!/bin/sh
+input="$1"
+shift
+echo "$@" > "$(dirname "$input")/args.txt"
+while [ $# -gt 0 ]; do
+  case "$1" in
+    --output_format) format="$2"; shift 2 ;;
+    --output_dir) dir="$2"; shift 2 ;;
+    *) shift ;;
+  esac
+done
+name="$(basename "$input" .aac)"
+if [ "$format" = json ]; then
+  printf '{"segments":[{"start":1.0,"end":2.5,"text":" Hello"},{"start":3.25,"end":4.0,"text":" world"}]}' > "$dir/$name.json"
+else
+  printf '1\n00:00:01,000 --> 00:00:02,500\nHello\n\n2\n00:00:03,250 --> 00:00:04,000\nworld\n' > "$dir/$name.srt"
+fi
+"#;
+
+  #[cfg(unix)]
+  #[tokio::test]
+  async fn transcribes_with_fake_recognizer() {
+    use std::os::unix::fs::PermissionsExt;
+    use crate::asr::transcribe;
+
+    let dir = std::env::temp_dir().join(format!("my-player-asr-{}", std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    let script = dir.join("fake-whisper");
+    fs::write(&script, FAKE_RECOGNIZER).unwrap();
+    fs::set_permissions(&script, fs::Permissions::from_mode(0o755)).unwrap();
+    let audio_path = dir.join("audio.aac");
+    fs::write(&audio_path, b"not really audio").unwrap();
+
+    for output_format in [LocalAsrOutput::Srt, LocalAsrOutput::Json] {
+      let provider = LocalProvider::new(LocalAsrSettings {
+        binary: script.to_string_lossy().to_string(),
+        flavor: LocalAsrFlavor::FasterWhisper,
+        model: Some("small".to_string()),
+        language: Some("en".to_string()),
+        threads: Some(2),
+        output_format,
+      });
+      let entries = transcribe(&provider, &audio_path.to_string_lossy()).await.unwrap();
+
+      assert_eq!(timings(&entries), [(1000, 2500, "Hello"), (3250, 4000, "world")]);
+      let args = fs::read_to_string(dir.join("args.txt")).unwrap();
+      assert!(args.contains("--model small --language en --threads 2"), "{}", args);
+      assert!(audio_path.is_file());
+      assert!(!provider.output_path(&audio_path).exists());
+    }
+
+    let _ = fs::remove_dir_all(&dir);
+  }
+}
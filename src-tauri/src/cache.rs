@@ -1,32 +1,357 @@
 use std::{
-  fs,
+  cmp::Reverse,
+  fs::{self, File},
+  io::{Read, Seek, SeekFrom},
   path::Path,
-  sync::Mutex,
-  collections::HashMap,
+  sync::{Arc, Mutex},
+  time::{SystemTime, UNIX_EPOCH},
+  future::Future,
+  collections::{HashMap, HashSet},
 };
 use regex::Regex;
+use sha2::{Digest, Sha256};
 use lazy_static::lazy_static;
+use tokio::sync::OnceCell;
 use base64::{engine::general_purpose::URL_SAFE, Engine as _};
+use serde::{Serialize, Deserialize};
+use crate::{
+  encoder::EncoderBackend,
+  probe::{PlaybackMode, TranscodeMode},
+  jobs::{cancel_job, is_job_running},
+  settings::settings,
+  paths::{cache_root, entry_dir, entry_file, entry_file_str},
+  playlist::{self, SegmentFormat},
+};
+
+const MANIFEST_FILE_NAME: &str = "manifest.json";
+const HASH_CHUNK_SIZE: u64 = 1024 * 1024;
+// Players poll the playlists of an unfinished entry every few seconds, an
+// access is only written out once it moved on by this many seconds.
+const ACCESS_SAVE_INTERVAL: u64 = 60;
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum CacheState {
+  InProgress,
+  Complete,
+  Failed,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Rendition {
+  pub name: String,
+  pub playlist: String,
+  pub width: Option<u32>,
+  pub height: Option<u32>,
+  pub bandwidth: Option<u64>,
+}
+
+/// An audio stream of the source. With several of them each is an alternate
+/// rendition with its own playlist, otherwise it is muxed into the video.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct AudioTrack {
+  pub index: usize,
+  /// Label the player lists the track under, unique within the entry.
+  pub name: String,
+  pub playlist: Option<String>,
+  pub language: Option<String>,
+  pub title: Option<String>,
+  pub codec: String,
+  pub channels: Option<u32>,
+  pub default: bool,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum SubtitleSource {
+  /// A text subtitle stream of the source, converted to WebVTT.
+  Embedded,
+  /// A subtitle file next to the source, converted to WebVTT.
+  Sidecar,
+  /// Generated by speech recognition.
+  Asr,
+}
+
+impl Default for SubtitleSource {
+  fn default() -> Self {
+    SubtitleSource::Asr
+  }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct SubtitleFile {
+  pub file: String,
+  pub language: Option<String>,
+  pub title: Option<String>,
+  #[serde(default)]
+  pub source: SubtitleSource,
+  /// The source stream an embedded subtitle is extracted from.
+  #[serde(default)]
+  pub stream_index: Option<usize>,
+  /// The file a sidecar subtitle is converted from.
+  #[serde(default)]
+  pub source_file: Option<String>,
+  /// The encoding a sidecar subtitle was read with.
+  #[serde(default)]
+  pub encoding: Option<String>,
+  /// Set by the user when detection picked the wrong encoding.
+  #[serde(default)]
+  pub encoding_override: Option<String>,
+}
 
+/// A cache entry, persisted as `manifest.json` inside its output directory.
+#[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct Cache {
-  pub duration: f64,
+  #[serde(skip)]
   pub output_dir_name: String,
   pub original_file_path: String,
+  pub source_size: u64,
+  pub source_mtime: u64,
+  pub content_hash: String,
+  pub duration: f64,
+  #[serde(default)]
+  pub codecs: Vec<String>,
+  #[serde(default)]
+  pub renditions: Vec<Rendition>,
+  /// Set when the renditions form an adaptive ladder listed by this playlist.
+  #[serde(default)]
+  pub master_playlist: Option<String>,
+  #[serde(default)]
+  pub audio_tracks: Vec<AudioTrack>,
+  #[serde(default)]
+  pub segment_format: SegmentFormat,
+  /// The playlist was written up front and segments are made where the player seeks.
+  #[serde(default)]
+  pub on_demand: bool,
+  #[serde(default)]
+  pub subtitles: Vec<SubtitleFile>,
+  pub encoder: Option<EncoderBackend>,
+  pub transcode_mode: Option<TranscodeMode>,
+  #[serde(default)]
+  pub playback_mode: PlaybackMode,
+  pub state: CacheState,
+  pub created_at: u64,
+  pub last_accessed: u64,
+  #[serde(default)]
+  pub pinned: bool,
+}
+
+#[derive(Serialize)]
+pub struct CacheEntryInfo {
+  input_path: String,
+  duration: f64,
+  disk_usage: u64,
+  has_subtitles: bool,
+  subtitles: Vec<SubtitleFile>,
+  codecs: Vec<String>,
+  playback_mode: PlaybackMode,
+  state: CacheState,
+  pinned: bool,
+  transcoding: bool,
+  created_at: u64,
+  last_accessed: u64,
+}
+
+#[derive(Serialize)]
+pub struct CacheListResponse {
+  success: bool,
+  message: String,
+  entries: Vec<CacheEntryInfo>,
+  total_size: u64,
 }
 
+#[derive(Serialize)]
+pub struct CacheEntryResponse {
+  success: bool,
+  message: String,
+  entry: Option<CacheEntryInfo>,
+}
+
+pub enum CacheLookup {
+  Hit,
+  Stale,
+  Miss,
+}
+
+type InFlight = Arc<OnceCell<Result<(), String>>>;
+
 lazy_static! {
   pub static ref CACHE_MAP: Mutex<HashMap<String, Cache>> = Mutex::new(HashMap::new());
+  static ref IN_FLIGHT: Mutex<HashMap<String, InFlight>> = Mutex::new(HashMap::new());
+  // The input open in the player, from `generate_hls` until `cancel_hls` or
+  // the next file is opened.
+  static ref OPEN_ENTRIES: Mutex<HashSet<String>> = Mutex::new(HashSet::new());
+  // Keeps manifest writes of the same entry in the order they were made.
+  static ref MANIFEST_WRITE: Mutex<()> = Mutex::new(());
+}
+
+impl Rendition {
+  pub fn source(width: Option<u32>, height: Option<u32>) -> Self {
+    Self {
+      name: "source".to_string(),
+      playlist: "playlist.m3u8".to_string(),
+      width,
+      height,
+      bandwidth: None,
+    }
+  }
+
+  /// The source resolution written to its own subdirectory, next to the
+  /// separate audio renditions.
+  pub fn source_variant(width: Option<u32>, height: Option<u32>, bandwidth: Option<u64>) -> Self {
+    Self {
+      name: "source".to_string(),
+      playlist: "source/playlist.m3u8".to_string(),
+      width,
+      height,
+      bandwidth,
+    }
+  }
+
+  /// A rung of the adaptive ladder, written to its own subdirectory.
+  pub fn scaled(name: &str, width: Option<u32>, height: u32, bandwidth: u64) -> Self {
+    Self {
+      name: name.to_string(),
+      playlist: format!("{}/playlist.m3u8", name),
+      width,
+      height: Some(height),
+      bandwidth: Some(bandwidth),
+    }
+  }
 }
 
-pub fn init_hashmap(dir_path: &str) -> Result<(), std::io::Error> {
-  let dir_path = Path::new(dir_path);
-  for entry in fs::read_dir(dir_path)? {
+impl Cache {
+  fn info(&self, input_path: &str) -> CacheEntryInfo {
+    CacheEntryInfo {
+      input_path: input_path.to_string(),
+      duration: self.duration,
+      disk_usage: dir_size(&entry_dir(&self.output_dir_name)),
+      has_subtitles: !self.subtitles.is_empty(),
+      subtitles: self.subtitles.clone(),
+      codecs: self.codecs.clone(),
+      playback_mode: self.playback_mode,
+      state: self.state,
+      pinned: self.pinned,
+      transcoding: is_job_running(input_path),
+      created_at: self.created_at,
+      last_accessed: self.last_accessed,
+    }
+  }
+
+  /// A fresh in-progress entry, fingerprinting the source file as it is now.
+  pub fn new(original_file_path: String, output_dir_name: String, duration: f64) -> Result<Self, std::io::Error> {
+    let (source_size, source_mtime) = source_fingerprint(&original_file_path)?;
+    let created_at = now();
+
+    Ok(Self {
+      content_hash: content_hash(&original_file_path)?,
+      output_dir_name,
+      original_file_path,
+      source_size,
+      source_mtime,
+      duration,
+      codecs: Vec::new(),
+      renditions: Vec::new(),
+      master_playlist: None,
+      audio_tracks: Vec::new(),
+      segment_format: SegmentFormat::Ts,
+      on_demand: false,
+      subtitles: Vec::new(),
+      encoder: None,
+      transcode_mode: None,
+      playback_mode: PlaybackMode::Hls,
+      state: CacheState::InProgress,
+      created_at,
+      last_accessed: created_at,
+      pinned: false,
+    })
+  }
+
+  /// The file the player is pointed at: the HLS playlist, or for direct play
+  /// the name the server maps onto the source file.
+  pub fn media_file_name(&self) -> String {
+    match self.playback_mode {
+      PlaybackMode::Hls => self.master_playlist.clone().unwrap_or_else(|| "playlist.m3u8".to_string()),
+      PlaybackMode::Direct => {
+        let is_webm = Path::new(&self.original_file_path)
+          .extension()
+          .map_or(false, |extension| extension.eq_ignore_ascii_case("webm"));
+        if is_webm { "source.webm" } else { "source.mp4" }.to_string()
+      },
+    }
+  }
+
+  /// The media playlist progress and resuming are judged by. Every rung of a
+  /// ladder is cut at the same points, so the first one stands for all.
+  pub fn playlist_path(&self) -> String {
+    let playlist = self.renditions.first().map_or("playlist.m3u8", |rendition| rendition.playlist.as_str());
+    entry_file_str(&self.output_dir_name, playlist)
+  }
+
+  pub fn save(&self) -> Result<(), std::io::Error> {
+    let _manifest_write = MANIFEST_WRITE.lock().unwrap();
+    self.write_manifest()
+  }
+
+  fn write_manifest(&self) -> Result<(), std::io::Error> {
+    let manifest_path = entry_file(&self.output_dir_name, MANIFEST_FILE_NAME);
+    let temp_path = entry_file(&self.output_dir_name, &(MANIFEST_FILE_NAME.to_string() + ".tmp"));
+    fs::write(&temp_path, serde_json::to_vec_pretty(self)?)?;
+    fs::rename(temp_path, manifest_path)
+  }
+}
+
+pub fn init_hashmap() -> Result<(), std::io::Error> {
+  for entry in fs::read_dir(cache_root())? {
     let entry = entry?;
     let path = entry.path();
 
     if path.is_dir() {
       if let Some(dir_name) = path.file_name() {
-        let cache = decode_dir_name_to_path(dir_name.to_string_lossy().into_owned()).unwrap();
+        let dir_name = dir_name.to_string_lossy().into_owned();
+        let mut cache = match load_manifest(&dir_name).or_else(|_| migrate_legacy_dir(&dir_name)) {
+          Ok(cache) => cache,
+          // The manifest is written before ffmpeg starts, so a hash-named
+          // directory without one is left over from a crash while preparing.
+          Err(_) if is_entry_dir_name(&dir_name) && !entry_file(&dir_name, MANIFEST_FILE_NAME).is_file() => {
+            if let Err(err) = fs::remove_dir_all(&path) {
+              println!("failed to remove orphaned cache entry {}: {}", dir_name, err);
+            }
+            continue;
+          },
+          Err(err) => {
+            println!("skipping unreadable cache entry {}: {}", dir_name, err);
+            continue;
+          },
+        };
+        let playlist_path = cache.playlist_path();
+
+        // An unfinished playlist means the app exited mid-transcode. Keep it
+        // for resuming only if there is something to resume from. On-demand
+        // playlists are complete from the start, so their segments are checked.
+        let is_finished = if cache.on_demand {
+          playlist::first_missing_segment(&playlist_path, 0).is_none()
+        } else {
+          playlist::is_finished(&playlist_path)
+        };
+        if cache.state == CacheState::Complete || is_finished {
+          cache.state = CacheState::Complete;
+        } else if cache.state == CacheState::InProgress
+          && Path::new(&cache.original_file_path).is_file()
+          && playlist::existing_segments(&playlist_path) > 0
+        {
+          cache.state = CacheState::InProgress;
+        } else {
+          if let Err(err) = fs::remove_dir_all(entry_dir(&cache.output_dir_name)) {
+            println!("failed to remove unfinished cache entry {}: {}", dir_name, err);
+          }
+          continue;
+        }
+        if let Err(err) = cache.save() {
+          println!("skipping cache entry {} with unwritable manifest: {}", dir_name, err);
+          continue;
+        }
+
         let mut cache_map = CACHE_MAP.lock().unwrap();
         let original_file_path = cache.original_file_path.clone();
         cache_map.insert(original_file_path, cache);
@@ -38,53 +363,513 @@ pub fn init_hashmap(dir_path: &str) -> Result<(), std::io::Error> {
   Ok(())
 }
 
+/// Runs `prepare` for the input unless a preparation of the same input is
+/// already in flight, in which case that one's outcome is awaited instead.
+pub async fn prepare_once<F, Fut>(input_path: &str, prepare: F) -> Result<(), String>
+where
+  F: FnOnce() -> Fut,
+  Fut: Future<Output = Result<(), String>>,
+{
+  let in_flight = {
+    let mut in_flight_map = IN_FLIGHT.lock().unwrap();
+    in_flight_map.entry(input_path.to_string()).or_default().clone()
+  };
+
+  let result = in_flight.get_or_init(prepare).await.clone();
+
+  let mut in_flight_map = IN_FLIGHT.lock().unwrap();
+  if in_flight_map.get(input_path).map_or(false, |current| Arc::ptr_eq(current, &in_flight)) {
+    in_flight_map.remove(input_path);
+  }
+  drop(in_flight_map);
+
+  result
+}
+
 pub fn generate_dir_name(input_path: &str) -> String {
-  URL_SAFE.encode(input_path)
+  let digest = format!("{:x}", Sha256::digest(input_path.as_bytes()));
+  digest[..16].to_string()
+}
+
+/// Directory for a new entry of the input, named after the input and when the
+/// entry was made. A regenerated entry thereby never serves different files
+/// under URLs the webview may have cached, and a new file showing up where an
+/// entry was moved from can't write into that entry's directory.
+pub fn new_dir_name(input_path: &str) -> String {
+  let generation = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |duration| duration.as_nanos());
+  let cache_map = CACHE_MAP.lock().unwrap();
+  let is_taken = |dir_name: &str| {
+    entry_dir(dir_name).exists() || cache_map.values().any(|cache| cache.output_dir_name == dir_name)
+  };
+  let mut dir_name = generate_dir_name(&format!("{}#{}", input_path, generation));
+  let mut attempt = 1;
+  while is_taken(&dir_name) {
+    dir_name = generate_dir_name(&format!("{}#{}#{}", input_path, generation, attempt));
+    attempt += 1;
+  }
+  drop(cache_map);
+
+  dir_name
+}
+
+// Whether the name has the shape `generate_dir_name` produces.
+fn is_entry_dir_name(dir_name: &str) -> bool {
+  dir_name.len() == 16 && dir_name.bytes().all(|byte| byte.is_ascii_digit() || (b'a'..=b'f').contains(&byte))
 }
 
 pub fn cache_map_insert(input_path: String, cache: Cache) -> Result<(), std::io::Error> {
+  cache.save()?;
   let mut cache_map = CACHE_MAP.lock().unwrap();
-  let duration_dir_name = URL_SAFE.encode("[duration=".to_string() + &cache.duration.clone().to_string() + "]");
-  let duration_dir_path = "hls/".to_string() + &cache.output_dir_name + "/" + &duration_dir_name;
-  fs::create_dir(&duration_dir_path).unwrap();
   cache_map.insert(input_path, cache);
   drop(cache_map);
 
   Ok(())
 }
 
-fn parse_cache(dir_name: &str, encoded_dir_name: &str) -> Result<Cache, std::io::Error> {
-  let original_file_path = encoded_dir_name.to_string();
-  let mut duration: f64 = 0.0;
-  let dir_path = "hls/".to_string() + dir_name;
-  let dir_path = Path::new(&dir_path);
-  let duration_re = Regex::new(r"\[duration=(\d+.\d+)\]").unwrap();
+pub fn cache_map_set_state(input_path: &str, state: CacheState) {
+  let mut cache_map = CACHE_MAP.lock().unwrap();
+  if let Some(cache) = cache_map.get_mut(input_path) {
+    cache.state = state;
+    let _ = cache.save();
+  }
+  drop(cache_map);
+}
 
-  for entry in fs::read_dir(dir_path)? {
-    let entry = entry?;
-    let path = entry.path();
+/// Records the encoder that ended up writing the entry, after a hardware
+/// encoder failed and the transcode fell back to software.
+pub fn cache_map_set_encoder(input_path: &str, encoder: EncoderBackend) {
+  let mut cache_map = CACHE_MAP.lock().unwrap();
+  if let Some(cache) = cache_map.get_mut(input_path) {
+    cache.encoder = Some(encoder);
+    let _ = cache.save();
+  }
+  drop(cache_map);
+}
 
-    if path.is_dir() {
-      if let Some(dir_name) = path.file_name() {
-        let dir_name = URL_SAFE.decode(dir_name.to_string_lossy().into_owned())
-          .map(|bytes| String::from_utf8_lossy(&bytes).to_string())
-          .unwrap();
-        if let Some(captures) = duration_re.captures(&dir_name) {
-          duration = captures[1].parse::<f64>().unwrap();
-        }
+pub fn cache_map_add_subtitle(input_path: &str, subtitle: SubtitleFile) {
+  let mut cache_map = CACHE_MAP.lock().unwrap();
+  if let Some(cache) = cache_map.get_mut(input_path) {
+    cache.subtitles.retain(|existing| existing.file != subtitle.file);
+    cache.subtitles.push(subtitle);
+    let _ = cache.save();
+  }
+  drop(cache_map);
+}
+
+/// Checks the entry for `input_path` against the source file on disk. A source
+/// that changed makes the entry stale; a source that is new to the cache may be
+/// a moved file, in which case an entry with the same content is taken over.
+pub fn cache_map_lookup(input_path: &str) -> CacheLookup {
+  let fingerprint = match source_fingerprint(input_path) {
+    Ok(fingerprint) => fingerprint,
+    // Without a source there is nothing to compare against, so serve what we have.
+    Err(_) => return if CACHE_MAP.lock().unwrap().contains_key(input_path) { CacheLookup::Hit } else { CacheLookup::Miss },
+  };
+  let (source_size, source_mtime) = fingerprint;
+
+  let known = {
+    let cache_map = CACHE_MAP.lock().unwrap();
+    cache_map.get(input_path).map(|cache| ((cache.source_size, cache.source_mtime), cache.content_hash.clone()))
+  };
+  if let Some((known_fingerprint, known_hash)) = known {
+    if known_fingerprint == fingerprint {
+      return CacheLookup::Hit;
+    }
+    // Touched but possibly identical, e.g. restored from a backup.
+    if content_hash(input_path).map_or(true, |hash| hash != known_hash) {
+      return CacheLookup::Stale;
+    }
+    let mut cache_map = CACHE_MAP.lock().unwrap();
+    if let Some(cache) = cache_map.get_mut(input_path) {
+      cache.source_size = source_size;
+      cache.source_mtime = source_mtime;
+      let _ = cache.save();
+    }
+    drop(cache_map);
+
+    return CacheLookup::Hit;
+  }
+
+  let moved_candidates: Vec<(String, String)> = {
+    let cache_map = CACHE_MAP.lock().unwrap();
+    cache_map.iter()
+      .filter(|(path, cache)| {
+        cache.source_size == source_size
+          && cache.state != CacheState::Failed
+          && !Path::new(path).exists()
+      })
+      .map(|(path, cache)| (path.clone(), cache.content_hash.clone()))
+      .collect()
+  };
+  if moved_candidates.is_empty() {
+    return CacheLookup::Miss;
+  }
+  let hash = match content_hash(input_path) {
+    Ok(hash) => hash,
+    Err(_) => return CacheLookup::Miss,
+  };
+
+  let moved_from = moved_candidates.into_iter().find(|(_, known_hash)| *known_hash == hash);
+  if let Some((old_path, _)) = moved_from {
+    let mut cache_map = CACHE_MAP.lock().unwrap();
+    if let Some(mut cache) = cache_map.remove(&old_path) {
+      cache.original_file_path = input_path.to_string();
+      cache.source_mtime = source_mtime;
+      let _ = cache.save();
+      cache_map.insert(input_path.to_string(), cache);
+      return CacheLookup::Hit;
+    }
+  }
+
+  CacheLookup::Miss
+}
+
+/// Called by the server for every file it serves from an entry. Playlist
+/// requests mark a new playback and are recorded as the entry's last access,
+/// at most once every `ACCESS_SAVE_INTERVAL` seconds.
+pub fn record_access(output_dir_name: &str, is_playlist: bool) {
+  if !is_playlist {
+    return;
+  }
+
+  let now = now();
+  let mut cache_map = CACHE_MAP.lock().unwrap();
+  let cache = match cache_map.values_mut().find(|cache| cache.output_dir_name == output_dir_name) {
+    Some(cache) if now >= cache.last_accessed + ACCESS_SAVE_INTERVAL => cache,
+    _ => return,
+  };
+  cache.last_accessed = now;
+  let cache = cache.clone();
+  // Taken before the cache lock is released, so a save made after this one
+  // can't be overwritten by it.
+  let manifest_write = MANIFEST_WRITE.lock().unwrap();
+  drop(cache_map);
+  let _ = cache.write_manifest();
+  drop(manifest_write);
+}
+
+pub fn cache_state_by_dir(output_dir_name: &str) -> Option<CacheState> {
+  let cache_map = CACHE_MAP.lock().unwrap();
+  cache_map.values()
+    .find(|cache| cache.output_dir_name == output_dir_name)
+    .map(|cache| cache.state)
+}
+
+/// Input path and playlist of an on-demand entry that is still missing segments.
+pub fn on_demand_by_dir(output_dir_name: &str) -> Option<(String, String)> {
+  let cache_map = CACHE_MAP.lock().unwrap();
+  cache_map.iter()
+    .find(|(_, cache)| cache.output_dir_name == output_dir_name && cache.on_demand && cache.state == CacheState::InProgress)
+    .map(|(input_path, cache)| (input_path.clone(), cache.playlist_path()))
+}
+
+/// Source file of a direct play entry, which the server exposes as `source.*`.
+pub fn direct_source_by_dir(output_dir_name: &str) -> Option<String> {
+  let cache_map = CACHE_MAP.lock().unwrap();
+  cache_map.values()
+    .find(|cache| cache.output_dir_name == output_dir_name && cache.playback_mode == PlaybackMode::Direct)
+    .map(|cache| cache.original_file_path.clone())
+}
+
+/// Marks the input as open in the player, which keeps its entry from being
+/// evicted however long playback is paused. The player shows one file at a
+/// time and may switch without cancelling, so opening one closes the others.
+pub fn set_entry_open(input_path: &str, open: bool) {
+  let mut open_entries = OPEN_ENTRIES.lock().unwrap();
+  if open {
+    open_entries.clear();
+    open_entries.insert(input_path.to_string());
+  } else {
+    open_entries.remove(input_path);
+  }
+  drop(open_entries);
+}
+
+fn is_in_use(input_path: &str) -> bool {
+  OPEN_ENTRIES.lock().unwrap().contains(input_path) || is_job_running(input_path)
+}
+
+/// Applies the configured quota: entries past the maximum age go first, then
+/// the least recently accessed ones until the cache fits the size limit.
+/// Pinned entries and entries being played or transcoded are never evicted.
+pub fn evict_cache() {
+  let settings = settings();
+  let caches: Vec<(String, String, u64, bool)> = {
+    let cache_map = CACHE_MAP.lock().unwrap();
+    cache_map.iter()
+      .map(|(input_path, cache)| (input_path.clone(), cache.output_dir_name.clone(), cache.last_accessed, cache.pinned))
+      .collect()
+  };
+  let mut entries: Vec<(String, String, u64, u64, bool)> = caches.into_iter()
+    .map(|(input_path, output_dir_name, last_accessed, pinned)| {
+      let size = dir_size(&entry_dir(&output_dir_name));
+      (input_path, output_dir_name, last_accessed, size, pinned)
+    })
+    .collect();
+  entries.sort_by_key(|(_, _, last_accessed, _, _)| *last_accessed);
+
+  let mut total_size: u64 = entries.iter().map(|(_, _, _, size, _)| size).sum();
+  let oldest_allowed = settings.cache_max_age_days.map(|days| now().saturating_sub(days * 24 * 60 * 60));
+
+  for (input_path, output_dir_name, last_accessed, size, pinned) in entries {
+    let is_expired = oldest_allowed.map_or(false, |oldest_allowed| last_accessed < oldest_allowed);
+    let is_over_quota = settings.cache_max_bytes.map_or(false, |max_bytes| total_size > max_bytes);
+    if !is_expired && !is_over_quota {
+      continue;
+    }
+    if pinned || is_in_use(&input_path) {
+      continue;
+    }
+
+    println!("evicting cached HLS for {}", input_path);
+    cache_map_remove(&input_path);
+    if fs::remove_dir_all(entry_dir(&output_dir_name)).is_ok() {
+      total_size = total_size.saturating_sub(size);
+    }
+  }
+}
+
+pub fn dir_size(path: &Path) -> u64 {
+  fs::read_dir(path)
+    .map(|entries| {
+      entries
+        .filter_map(|entry| entry.ok())
+        .map(|entry| {
+          let path = entry.path();
+          if path.is_dir() {
+            dir_size(&path)
+          } else {
+            entry.metadata().map(|metadata| metadata.len()).unwrap_or(0)
+          }
+        })
+        .sum()
+    })
+    .unwrap_or(0)
+}
+
+/// Forgets the entry and returns its output directory name.
+pub fn cache_map_remove(input_path: &str) -> Option<String> {
+  let mut cache_map = CACHE_MAP.lock().unwrap();
+  let cache = cache_map.remove(input_path);
+  drop(cache_map);
+
+  cache.map(|cache| cache.output_dir_name)
+}
+
+/// Size and modification time (unix seconds) of the source file.
+pub fn source_fingerprint(path: &str) -> Result<(u64, u64), std::io::Error> {
+  let metadata = fs::metadata(path)?;
+  Ok((metadata.len(), unix_time(metadata.modified()?)))
+}
+
+/// Hashes the file size together with its first and last MiB. Cheap enough to
+/// run on every lookup, yet changes whenever a different video is put in place.
+pub fn content_hash(path: &str) -> Result<String, std::io::Error> {
+  let mut file = File::open(path)?;
+  let size = file.metadata()?.len();
+  let mut hasher = Sha256::new();
+  hasher.update(size.to_le_bytes());
+
+  let mut buffer = vec![0; HASH_CHUNK_SIZE.min(size) as usize];
+  file.read_exact(&mut buffer)?;
+  hasher.update(&buffer);
+  if size > HASH_CHUNK_SIZE {
+    file.seek(SeekFrom::End(-(buffer.len() as i64)))?;
+    file.read_exact(&mut buffer)?;
+    hasher.update(&buffer);
+  }
+
+  Ok(format!("{:x}", hasher.finalize()))
+}
+
+pub fn now() -> u64 {
+  unix_time(SystemTime::now())
+}
+
+fn unix_time(time: SystemTime) -> u64 {
+  time.duration_since(UNIX_EPOCH).map(|duration| duration.as_secs()).unwrap_or(0)
+}
+
+fn load_manifest(dir_name: &str) -> Result<Cache, Box<dyn std::error::Error>> {
+  let manifest_path = entry_file(dir_name, MANIFEST_FILE_NAME);
+  let mut cache = serde_json::from_str::<Cache>(&fs::read_to_string(manifest_path)?)?;
+  cache.output_dir_name = dir_name.to_string();
+
+  Ok(cache)
+}
+
+// Entries written before manifests existed are named after the base64 encoded
+// source path and keep the duration in a `[duration=...]` subdirectory.
+fn migrate_legacy_dir(dir_name: &str) -> Result<Cache, Box<dyn std::error::Error>> {
+  let original_file_path = String::from_utf8(URL_SAFE.decode(dir_name)?)?;
+  let dir_path = entry_dir(dir_name);
+  let duration_re = Regex::new(r"\[duration=(\d+(\.\d+)?)\]").unwrap();
+  let mut duration: f64 = 0.0;
+
+  for entry in fs::read_dir(&dir_path)? {
+    let path = entry?.path();
+    if !path.is_dir() {
+      continue;
+    }
+    if let Some(sub_dir_name) = path.file_name() {
+      let decoded = URL_SAFE.decode(sub_dir_name.to_string_lossy().as_bytes())
+        .map(|bytes| String::from_utf8_lossy(&bytes).to_string())
+        .unwrap_or_default();
+      if let Some(captures) = duration_re.captures(&decoded) {
+        duration = captures[1].parse::<f64>().unwrap_or(0.0);
+        fs::remove_dir_all(&path)?;
       }
     }
   }
 
-  Ok(Cache {
-    duration,
+  let created_at = fs::metadata(&dir_path)
+    .and_then(|metadata| metadata.modified())
+    .map(unix_time)
+    .unwrap_or_else(|_| now());
+  let (source_size, source_mtime) = source_fingerprint(&original_file_path).unwrap_or((0, 0));
+  let mut subtitles = Vec::new();
+  if dir_path.join("subtitle.vtt").is_file() {
+    subtitles.push(SubtitleFile {
+      file: "subtitle.vtt".to_string(),
+      language: None,
+      title: None,
+      source: SubtitleSource::Asr,
+      stream_index: None,
+      source_file: None,
+      encoding: None,
+      encoding_override: None,
+    });
+  }
+
+  let mut output_dir_name = generate_dir_name(&original_file_path);
+  if fs::rename(&dir_path, entry_dir(&output_dir_name)).is_err() {
+    output_dir_name = dir_name.to_string();
+  }
+
+  let cache = Cache {
+    output_dir_name,
+    content_hash: content_hash(&original_file_path).unwrap_or_default(),
     original_file_path,
-    output_dir_name: dir_name.to_owned(),
+    source_size,
+    source_mtime,
+    duration,
+    codecs: Vec::new(),
+    renditions: vec![Rendition::source(None, None)],
+    master_playlist: None,
+    audio_tracks: Vec::new(),
+    segment_format: SegmentFormat::Ts,
+    on_demand: false,
+    subtitles,
+    encoder: None,
+    transcode_mode: None,
+    playback_mode: PlaybackMode::Hls,
+    state: CacheState::InProgress,
+    created_at,
+    last_accessed: created_at,
+    pinned: false,
+  };
+  cache.save()?;
+
+  Ok(cache)
+}
+
+#[tauri::command]
+pub async fn pin_cache_entry(input_path: String, pinned: bool) -> Result<bool, String> {
+  let mut cache_map = CACHE_MAP.lock().unwrap();
+  let cache = cache_map.get_mut(&input_path).ok_or("No cache entry for this file")?;
+  cache.pinned = pinned;
+  cache.save().map_err(|err| err.to_string())?;
+
+  Ok(pinned)
+}
+
+// Disk usage is summed up after the lock is released, walking every entry
+// takes a while.
+fn cache_entry_infos() -> Vec<CacheEntryInfo> {
+  let caches: Vec<(String, Cache)> = {
+    let cache_map = CACHE_MAP.lock().unwrap();
+    cache_map.iter().map(|(input_path, cache)| (input_path.clone(), cache.clone())).collect()
+  };
+  let mut entries: Vec<CacheEntryInfo> = caches.iter()
+    .map(|(input_path, cache)| cache.info(input_path))
+    .collect();
+  entries.sort_by_key(|entry| Reverse(entry.last_accessed));
+
+  entries
+}
+
+fn cache_list_response(message: &str) -> CacheListResponse {
+  let entries = cache_entry_infos();
+
+  CacheListResponse {
+    success: true,
+    message: message.to_string(),
+    total_size: entries.iter().map(|entry| entry.disk_usage).sum(),
+    entries,
+  }
+}
+
+// Stops any transcode still writing into the entry before deleting its files,
+// so the directory is not recreated behind our back.
+async fn delete_entry(input_path: &str) -> Result<(), std::io::Error> {
+  cancel_job(input_path).await;
+  match cache_map_remove(input_path) {
+    Some(output_dir_name) => fs::remove_dir_all(entry_dir(&output_dir_name)),
+    None => Ok(()),
+  }
+}
+
+#[tauri::command]
+pub async fn list_cache() -> Result<CacheListResponse, String> {
+  Ok(cache_list_response("Cache listed successfully"))
+}
+
+#[tauri::command]
+pub async fn get_cache_entry(input_path: String) -> Result<CacheEntryResponse, String> {
+  let cache = CACHE_MAP.lock().unwrap().get(&input_path).cloned();
+  let entry = cache.map(|cache| cache.info(&input_path));
+
+  Ok(CacheEntryResponse {
+    success: entry.is_some(),
+    message: if entry.is_some() { "Cache entry found" } else { "No cache entry for this file" }.to_string(),
+    entry,
   })
 }
 
-fn decode_dir_name_to_path(dir_name: String) -> Result<Cache, base64::DecodeError> {
-  URL_SAFE.decode(dir_name.clone())
-    .map(|bytes| String::from_utf8_lossy(&bytes).to_string())
-    .map(|encoded_dir_name| parse_cache(&dir_name, &encoded_dir_name).unwrap())
-}
\ No newline at end of file
+#[tauri::command]
+pub async fn delete_cache_entry(input_path: String) -> Result<CacheEntryResponse, String> {
+  let cache = CACHE_MAP.lock().unwrap().get(&input_path).cloned();
+  let entry = cache.map(|cache| cache.info(&input_path));
+  if entry.is_none() {
+    return Ok(CacheEntryResponse {
+      success: false,
+      message: "No cache entry for this file".to_string(),
+      entry,
+    });
+  }
+  delete_entry(&input_path).await.map_err(|err| err.to_string())?;
+
+  Ok(CacheEntryResponse {
+    success: true,
+    message: "Cache entry deleted".to_string(),
+    entry,
+  })
+}
+
+/// Deletes every entry, keeping pinned ones unless `include_pinned` is set.
+#[tauri::command]
+pub async fn clear_cache(include_pinned: Option<bool>) -> Result<CacheListResponse, String> {
+  let include_pinned = include_pinned.unwrap_or(false);
+  let input_paths: Vec<String> = {
+    let cache_map = CACHE_MAP.lock().unwrap();
+    cache_map.iter()
+      .filter(|(_, cache)| include_pinned || !cache.pinned)
+      .map(|(input_path, _)| input_path.clone())
+      .collect()
+  };
+  for input_path in input_paths {
+    delete_entry(&input_path).await.map_err(|err| err.to_string())?;
+  }
+
+  Ok(cache_list_response("Cache cleared"))
+}
@@ -0,0 +1,145 @@
+use serde::{Serialize, Deserialize};
+use std::process::Stdio;
+use lazy_static::lazy_static;
+use tokio::{process::Command, sync::OnceCell};
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum EncoderBackend {
+  Nvenc,
+  Vaapi,
+  Qsv,
+  VideoToolbox,
+  Software,
+}
+
+// Ordered from most to least preferred.
+const BACKEND_PRIORITY: [EncoderBackend; 5] = [
+  EncoderBackend::Nvenc,
+  EncoderBackend::VideoToolbox,
+  EncoderBackend::Qsv,
+  EncoderBackend::Vaapi,
+  EncoderBackend::Software,
+];
+
+const VAAPI_DEVICE: &str = "/dev/dri/renderD128";
+
+lazy_static! {
+  static ref AVAILABLE_BACKENDS: OnceCell<Vec<EncoderBackend>> = OnceCell::new();
+}
+
+impl EncoderBackend {
+  pub fn encoder_name(&self) -> &'static str {
+    match self {
+      EncoderBackend::Nvenc => "h264_nvenc",
+      EncoderBackend::Vaapi => "h264_vaapi",
+      EncoderBackend::Qsv => "h264_qsv",
+      EncoderBackend::VideoToolbox => "h264_videotoolbox",
+      EncoderBackend::Software => "libx264",
+    }
+  }
+
+  pub fn is_hardware(&self) -> bool {
+    *self != EncoderBackend::Software
+  }
+
+  /// Arguments placed before `-i`, setting up hardware decoding or the device.
+  pub fn input_args(&self) -> Vec<&'static str> {
+    match self {
+      EncoderBackend::Nvenc => vec!["-hwaccel", "cuda", "-hwaccel_output_format", "cuda"],
+      EncoderBackend::Vaapi => vec!["-vaapi_device", VAAPI_DEVICE],
+      EncoderBackend::VideoToolbox => vec!["-hwaccel", "videotoolbox"],
+      EncoderBackend::Qsv | EncoderBackend::Software => vec![],
+    }
+  }
+
+  /// Arguments placed before `-i` when frames are decoded and scaled in software.
+  pub fn device_args(&self) -> Vec<&'static str> {
+    match self {
+      EncoderBackend::Vaapi => vec!["-vaapi_device", VAAPI_DEVICE],
+      _ => vec![],
+    }
+  }
+
+  /// Filter that hands software frames over in the format the encoder expects.
+  pub fn upload_filter(&self) -> Option<&'static str> {
+    match self {
+      EncoderBackend::Vaapi => Some("format=nv12,hwupload"),
+      EncoderBackend::Qsv => Some("format=nv12"),
+      _ => None,
+    }
+  }
+
+  pub fn codec_args(&self) -> Vec<&'static str> {
+    match self {
+      EncoderBackend::Software => vec!["-c:v", "libx264", "-preset", "veryfast"],
+      _ => vec!["-c:v", self.encoder_name()],
+    }
+  }
+
+  /// Arguments placed after `-i`, selecting the video encoder.
+  pub fn output_args(&self) -> Vec<&'static str> {
+    let mut args = match self {
+      EncoderBackend::Vaapi => vec!["-vf", "format=nv12,hwupload"],
+      EncoderBackend::Qsv => vec!["-pix_fmt", "nv12"],
+      _ => vec![],
+    };
+    args.extend(self.codec_args());
+
+    args
+  }
+
+  // A one frame test encode: being listed by `ffmpeg -encoders` only means the
+  // encoder was compiled in, not that the machine has a device for it.
+  async fn is_usable(&self) -> bool {
+    let mut args = vec!["-hide_banner", "-loglevel", "error"];
+    if *self == EncoderBackend::Vaapi {
+      args.extend(["-vaapi_device", VAAPI_DEVICE]);
+    }
+    args.extend(["-f", "lavfi", "-i", "color=black:s=256x256:d=0.1", "-frames:v", "1"]);
+    args.extend(self.output_args());
+    args.extend(["-f", "null", "-"]);
+
+    Command::new("ffmpeg")
+      .args(args)
+      .stdout(Stdio::null())
+      .stderr(Stdio::null())
+      .status()
+      .await
+      .map(|status| status.success())
+      .unwrap_or(false)
+  }
+}
+
+async fn probe_backends() -> Vec<EncoderBackend> {
+  let listed = match Command::new("ffmpeg").args(["-hide_banner", "-encoders"]).output().await {
+    Ok(output) => String::from_utf8_lossy(&output.stdout).into_owned(),
+    Err(_) => String::new(),
+  };
+
+  let mut backends = Vec::new();
+  for backend in BACKEND_PRIORITY {
+    if !backend.is_hardware() {
+      continue;
+    }
+    let is_listed = listed
+      .lines()
+      .any(|line| line.split_whitespace().nth(1) == Some(backend.encoder_name()));
+    if is_listed && backend.is_usable().await {
+      backends.push(backend);
+    }
+  }
+  backends.push(EncoderBackend::Software);
+  println!("available encoder backends: {:?}", backends);
+
+  backends
+}
+
+/// Encoder backends that work on this machine, best first. Software is always last.
+pub async fn available_backends() -> &'static [EncoderBackend] {
+  AVAILABLE_BACKENDS.get_or_init(probe_backends).await
+}
+
+pub async fn best_backend() -> EncoderBackend {
+  available_backends().await[0]
+}
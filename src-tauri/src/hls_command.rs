@@ -1,162 +1,1039 @@
 use serde::Serialize;
 use std::{
+  cmp::Reverse,
   fs,
-  time::Duration,
+  path::Path,
+  collections::HashSet,
   process::Stdio,
+  time::{Duration, Instant},
 };
 use regex::Regex;
-use tokio::process::Command;
-use tokio::io::{BufReader, AsyncBufReadExt};
+use tauri::Window;
+use tokio::process::{Child, ChildStderr, Command};
+use tokio::io::{BufReader, AsyncBufReadExt, Lines};
+use tokio::sync::watch;
 use crate::{
   server::get_file_url,
+  encoder::{EncoderBackend, best_backend},
+  probe::{probe, MediaInfo, PlaybackMode, TranscodeMode, TranscodePlan},
+  settings::{settings, RenditionProfile},
+  progress::ProgressReporter,
+  subtitle_tracks::{embedded_subtitles, extract_embedded_subtitles},
+  jobs::{
+    register_job,
+    attach_task,
+    attach_seek,
+    remove_idle_job,
+    request_segment,
+    cancel_job,
+    cancelled,
+    is_job_running,
+    running_jobs,
+    wait_or_kill,
+  },
+  playlist::{self, SegmentOptions},
+  paths::{entry_dir, entry_file, entry_file_str},
   cache::{
+    AudioTrack,
     Cache,
+    CacheLookup,
+    CacheState,
+    Rendition,
+    SubtitleSource,
     CACHE_MAP,
     cache_map_insert,
-    generate_dir_name,
+    cache_map_lookup,
+    cache_map_remove,
+    cache_map_set_state,
+    cache_map_set_encoder,
+    on_demand_by_dir,
+    evict_cache,
+    prepare_once,
+    set_entry_open,
+    new_dir_name,
   }
 };
 
+// Where an on-demand ffmpeg run writes its own playlist. The player gets the
+// full playlist written up front instead.
+const ON_DEMAND_PLAYLIST: &str = "transcode.m3u8";
+// A seek this few segments past what the running ffmpeg is writing is waited
+// for rather than restarting it.
+const SEEK_LOOKAHEAD: usize = 3;
+const SEGMENT_WAIT_TIMEOUT: Duration = Duration::from_secs(60);
+
+#[derive(Clone)]
 struct FFmpegCommand {
   input_path: String,
   output_dir_name: String,
+  backend: EncoderBackend,
+  plan: TranscodePlan,
+  /// Rungs to scale the video into. Empty for a single source rendition.
+  ladder: Vec<RenditionProfile>,
+  segments: SegmentOptions,
+  /// Segments are made where the player seeks to instead of start to end.
+  on_demand: bool,
+  duration: f64,
+  window: Window,
+  cancel: watch::Receiver<bool>,
 }
 
 impl FFmpegCommand {
+  #[allow(clippy::too_many_arguments)]
   fn new(
     input_path: String,
     output_dir_name: String,
+    backend: EncoderBackend,
+    plan: TranscodePlan,
+    ladder: Vec<RenditionProfile>,
+    segments: SegmentOptions,
+    on_demand: bool,
+    duration: f64,
+    window: Window,
+    cancel: watch::Receiver<bool>,
   ) -> Result<Self, ()> {
     Ok(Self {
       input_path,
-      output_dir_name
+      output_dir_name,
+      backend,
+      plan,
+      ladder,
+      segments,
+      on_demand,
+      duration,
+      window,
+      cancel,
     })
   }
 
-  pub async fn execute(&mut self) -> Result<f64, Box<dyn std::error::Error>> {
-    let output_segement_path = "hls/".to_string() + &self.output_dir_name + "/%03d.ts";
-    let output_playlist_path = "hls/".to_string() + &self.output_dir_name + "/playlist.m3u8";
-    let output_audio_path = "hls/".to_string() + &self.output_dir_name + "/audio.aac";
-
-    let mut transcode_cmd = Command::new("ffmpeg");
-    transcode_cmd
-      .args([
-        "-hwaccel", "cuda",
-        "-hwaccel_output_format", "cuda",
-        "-i", &self.input_path,
-        "-c:v", "h264_nvenc",
-        "-c:a", "aac",
-        "-hls_time", "10",
-        "-hls_list_size", "0",
-        "-hls_segment_filename", &output_segement_path,
-        &output_playlist_path,
-      ])
-      .stderr(Stdio::piped());
-
-    let mut audio_extrac_cmd = Command::new("ffmpeg")
-      .args([
-        "-i", &self.input_path,
-        "-map", "0:a",
-        "-c:a", "aac",
-        "-ar", "16000",
-        "-ac", "2",
-        &output_audio_path,
-      ])
-      .spawn()
-      .expect("Failed to spawn audio extract command");
-
-    let mut child = transcode_cmd.spawn().expect("Failed to spawn command");
-    let stdout = child.stderr.take().expect("Failed to open stdout");
-    let mut reader = BufReader::new(stdout).lines();
-
-    tokio::spawn(async move {
-      let status = child.wait().await
-        .expect("child process encountered an error");
-
-      println!("child status was: {}", status);
-    });
-    tokio::spawn(async move {
-      audio_extrac_cmd.wait().await.expect("Failed to extract audio");
-    });
+  // The audio track the subtitle recognition works on, extracted next to the stream.
+  fn extract_audio(&self) -> Result<(), std::io::Error> {
+    match self.plan.audio_index {
+      Some(audio_index) => spawn_audio_extract(&self.input_path, &self.output_dir_name, audio_index, self.cancel.clone()),
+      None => Ok(()),
+    }
+  }
+
+  pub async fn execute(&mut self, resume_point: Option<(usize, f64)>) -> Result<(), Box<dyn std::error::Error>> {
+    self.extract_audio()?;
+    if self.on_demand && !Path::new(&self.playlist_path()).is_file() {
+      playlist::write_vod_playlist(&self.playlist_path(), &self.segments, self.duration)?;
+    }
+
+    // ffmpeg writes the master playlist of a ladder once every rung has
+    // started. With separate audio renditions the master is written up front,
+    // so every media playlist has to show up instead.
+    let (playlist_regex, expected_playlists) = if !self.ladder.is_empty() && !self.has_multiple_audio() {
+      (Regex::new(r"Opening '(.+?master\.m3u8)(\.tmp)?' for writing").unwrap(), 1)
+    } else {
+      (Regex::new(r"Opening '(.+?\.m3u8)\.tmp' for writing").unwrap(), self.media_playlist_paths().len())
+    };
+    loop {
+      let mut child = self.spawn_transcode(resume_point)?;
+      let stderr = child.stderr.take().expect("Failed to open stderr");
+      let mut reader = BufReader::new(stderr).lines();
+
+      let mut has_playlist = false;
+      let mut opened_playlists = HashSet::new();
+      loop {
+        tokio::select! {
+          line = reader.next_line() => match line? {
+            Some(line) => {
+              if let Some(captures) = playlist_regex.captures(&line) {
+                opened_playlists.insert(captures[1].to_string());
+                if opened_playlists.len() >= expected_playlists {
+                  has_playlist = true;
+                  break;
+                }
+              }
+            },
+            None => break,
+          },
+          _ = cancelled(&mut self.cancel) => {
+            let _ = child.kill().await;
+            return Err("Transcode cancelled".into());
+          },
+        }
+      }
+
+      if has_playlist && self.on_demand {
+        drain_stderr(reader);
+        let start_index = resume_point.map_or(0, |(index, _)| index);
+        let (seek, seek_rx) = watch::channel(start_index);
+        attach_seek(&self.input_path, seek);
+        attach_task(&self.input_path, tokio::spawn(self.clone().run_on_demand(child, start_index, seek_rx)));
+
+        return Ok(())
+      }
+
+      if has_playlist {
+        drain_stderr(reader);
+        let mut cmd = self.clone();
+        attach_task(&self.input_path, tokio::spawn(async move {
+          let status = match wait_or_kill(&mut child, &mut cmd.cancel).await {
+            Some(status) => status,
+            None => return,
+          };
+
+          println!("child status was: {}", status);
+          let mut succeeded = status.success();
+          if !succeeded && cmd.uses_hardware() {
+            println!("{} encoder failed, falling back to software", cmd.backend.encoder_name());
+            let mut fallback_cmd = FFmpegCommand { backend: EncoderBackend::Software, ..cmd.clone() };
+            let resume_point = playlist::completed_segments(&fallback_cmd.playlist_path());
+            if let Ok(mut child) = fallback_cmd.spawn_transcode(Some(resume_point)) {
+              cache_map_set_encoder(&cmd.input_path, EncoderBackend::Software);
+              if let Some(stderr) = child.stderr.take() {
+                drain_stderr(BufReader::new(stderr).lines());
+              }
+              match wait_or_kill(&mut child, &mut fallback_cmd.cancel).await {
+                Some(status) => {
+                  println!("fallback child status was: {}", status);
+                  succeeded = status.success();
+                },
+                None => return,
+              }
+            }
+          }
+          if succeeded {
+            for playlist_path in cmd.media_playlist_paths() {
+              match playlist::fix_target_duration(&playlist_path) {
+                Ok(Some(target_duration)) => println!("raised target duration of {} to {}", playlist_path, target_duration),
+                Ok(None) => (),
+                Err(err) => println!("failed to check target duration of {}: {}", playlist_path, err),
+              }
+            }
+          }
+          cache_map_set_state(&cmd.input_path, if succeeded { CacheState::Complete } else { CacheState::Failed });
+          evict_cache();
+        }));
+
+        return Ok(())
+      }
+
+      let status = child.wait().await?;
+      if !self.uses_hardware() {
+        return Err(format!("ffmpeg exited before writing a playlist: {}", status).into());
+      }
+      println!("{} encoder failed, falling back to software", self.backend.encoder_name());
+      self.backend = EncoderBackend::Software;
+      cache_map_set_encoder(&self.input_path, self.backend);
+    }
+  }
+
+  // Keeps an on-demand transcode going until every listed segment is written.
+  // ffmpeg is restarted where the player seeks to and skips over segments an
+  // earlier run already wrote.
+  async fn run_on_demand(mut self, mut child: Child, mut start_index: usize, mut seek: watch::Receiver<usize>) {
+    let playlist_path = self.playlist_path();
+    let mut ticker = tokio::time::interval(Duration::from_secs(1));
+
+    loop {
+      let restart_at = tokio::select! {
+        status = child.wait() => {
+          let succeeded = status.map_or(false, |status| status.success());
+          println!("on-demand child exited, success: {}", succeeded);
+          if !succeeded && self.uses_hardware() {
+            println!("{} encoder failed, falling back to software", self.backend.encoder_name());
+            self.backend = EncoderBackend::Software;
+            cache_map_set_encoder(&self.input_path, self.backend);
+            playlist::first_missing_segment(&playlist_path, start_index)
+              .or_else(|| playlist::first_missing_segment(&playlist_path, 0))
+          } else if !succeeded {
+            cache_map_set_state(&self.input_path, CacheState::Failed);
+            evict_cache();
+            return;
+          } else {
+            // Listed segments past the end ffmpeg reached don't exist in the
+            // source, only gaps before where this run started are left.
+            playlist::first_missing_segment(&playlist_path, 0).filter(|index| *index < start_index)
+          }
+        },
+        Ok(()) = seek.changed() => {
+          let index = *seek.borrow();
+          let position = start_index + self.produced_segments();
+          if index >= start_index && index <= position + SEEK_LOOKAHEAD {
+            continue;
+          }
+          playlist::first_missing_segment(&playlist_path, index)
+            .or_else(|| playlist::first_missing_segment(&playlist_path, 0))
+        },
+        _ = ticker.tick() => {
+          let position = start_index + self.produced_segments();
+          match playlist::first_missing_segment(&playlist_path, position) {
+            Some(index) if index == position => continue,
+            Some(index) => Some(index),
+            None => playlist::first_missing_segment(&playlist_path, 0),
+          }
+        },
+        _ = cancelled(&mut self.cancel) => {
+          let _ = child.kill().await;
+          return;
+        },
+      };
+
+      let _ = child.kill().await;
+      match restart_at {
+        Some(index) => {
+          let offset = index as f64 * self.segments.duration as f64;
+          match self.spawn_transcode(Some((index, offset))) {
+            Ok(mut restarted) => {
+              if let Some(stderr) = restarted.stderr.take() {
+                drain_stderr(BufReader::new(stderr).lines());
+              }
+              child = restarted;
+              start_index = index;
+            },
+            Err(err) => {
+              println!("failed to restart on-demand transcode: {}", err);
+              cache_map_set_state(&self.input_path, CacheState::Failed);
+              evict_cache();
+              return;
+            },
+          }
+        },
+        None => {
+          let _ = fs::remove_file(entry_file(&self.output_dir_name, ON_DEMAND_PLAYLIST));
+          cache_map_set_state(&self.input_path, CacheState::Complete);
+          evict_cache();
+          return;
+        },
+      }
+    }
+  }
+
+  // Segments the running on-demand ffmpeg has finished since it was started.
+  fn produced_segments(&self) -> usize {
+    playlist::completed_segments(&entry_file_str(&self.output_dir_name, ON_DEMAND_PLAYLIST)).0
+  }
+
+  fn segment_files(&self) -> Vec<String> {
+    (0..self.segments.count(self.duration))
+      .map(|index| self.segments.file_name(index))
+      .collect()
+  }
+
+  fn has_multiple_audio(&self) -> bool {
+    self.plan.audio_indexes.len() > 1
+  }
+
+  // Subdirectories the video variants are written to. A single variant with
+  // muxed audio is written flat into the entry instead.
+  fn video_variants(&self) -> Vec<String> {
+    if !self.ladder.is_empty() {
+      self.ladder.iter().map(|rung| rung.name.clone()).collect()
+    } else if self.has_multiple_audio() {
+      vec!["source".to_string()]
+    } else {
+      Vec::new()
+    }
+  }
+
+  fn audio_variants(&self) -> Vec<String> {
+    if self.has_multiple_audio() {
+      (0..self.plan.audio_indexes.len()).map(audio_variant_name).collect()
+    } else {
+      Vec::new()
+    }
+  }
+
+  fn playlist_path(&self) -> String {
+    match self.video_variants().first() {
+      Some(variant) => entry_file_str(&self.output_dir_name, &format!("{}/playlist.m3u8", variant)),
+      None => entry_file_str(&self.output_dir_name, "playlist.m3u8"),
+    }
+  }
+
+  fn media_playlist_paths(&self) -> Vec<String> {
+    let variants = [self.video_variants(), self.audio_variants()].concat();
+    if variants.is_empty() {
+      return vec![self.playlist_path()];
+    }
+
+    variants.iter()
+      .map(|variant| entry_file_str(&self.output_dir_name, &format!("{}/playlist.m3u8", variant)))
+      .collect()
+  }
 
-    let playlist_regex = Regex::new(r"Opening '.+?m3u8.tmp' for writing").unwrap();
-    let duration_regex = Regex::new(r"Duration: (\d{2}):(\d{2}):(\d{2}\.\d{2})").unwrap();
-    let mut duration: f64 = 0.0;
-    while let Some(line) = reader.next_line().await? {
-      if let Some(captures) = duration_regex.captures(&line) {
-        let hours = captures[1].parse::<u64>().unwrap();
-        let minutes = captures[2].parse::<u64>().unwrap();
-        let seconds = captures[3].parse::<f64>().unwrap();
+  fn renditions(&self, media_info: &MediaInfo) -> Vec<Rendition> {
+    let video = self.plan.video_index.and_then(|index| media_info.stream(index));
+    let width = video.and_then(|stream| stream.width);
+    let height = video.and_then(|stream| stream.height);
+    if self.ladder.is_empty() && self.has_multiple_audio() {
+      let bandwidth = video.and_then(|stream| stream.bit_rate()).or_else(|| media_info.bit_rate());
+      return vec![Rendition::source_variant(width, height, bandwidth)];
+    }
+    if self.ladder.is_empty() {
+      return vec![Rendition::source(width, height)];
+    }
+
+    self.ladder.iter()
+      .map(|rung| {
+        let scaled_width = match (width, height) {
+          (Some(width), Some(height)) if height > 0 => Some((width as u64 * rung.height as u64 / height as u64) as u32 / 2 * 2),
+          _ => None,
+        };
+        let bandwidth = (rung.video_bitrate as u64 + rung.audio_bitrate as u64) * 1000;
+        Rendition::scaled(&rung.name, scaled_width, rung.height, bandwidth)
+      })
+      .collect()
+  }
+
+  // Splits the video into one scaled stream per rung. Keyframes are forced on
+  // the segment boundaries so every rung is cut at the same points.
+  fn ladder_args(&self) -> Vec<String> {
+    let mut args: Vec<String> = Vec::new();
+    let upload_filter = self.backend.upload_filter().map(|filter| format!(",{}", filter)).unwrap_or_default();
+    let split_outputs = (0..self.ladder.len()).map(|i| format!("[s{}]", i)).collect::<Vec<_>>().concat();
+    let mut filter = format!("[0:{}]split={}{}", self.plan.video_index.unwrap_or(0), self.ladder.len(), split_outputs);
+    for (i, rung) in self.ladder.iter().enumerate() {
+      filter += &format!(";[s{}]scale=-2:{}{}[v{}]", i, rung.height, upload_filter, i);
+    }
+    args.extend(["-filter_complex".to_string(), filter]);
 
-        duration = Duration::new(hours * 3600 + minutes * 60 + seconds.trunc() as u64, (seconds.fract() * 1e9) as u32).as_secs_f64();
+    for i in 0..self.ladder.len() {
+      args.extend(["-map".to_string(), format!("[v{}]", i)]);
+    }
+    if let (false, Some(audio_index)) = (self.has_multiple_audio(), self.plan.audio_index) {
+      for _ in &self.ladder {
+        args.extend(["-map".to_string(), format!("0:{}", audio_index)]);
       }
-      if playlist_regex.is_match(&line) {
-        return Ok(duration)
+    }
+
+    args.extend(self.backend.codec_args().into_iter().map(String::from));
+    for (i, rung) in self.ladder.iter().enumerate() {
+      args.extend([
+        format!("-b:v:{}", i), format!("{}k", rung.video_bitrate),
+        format!("-maxrate:v:{}", i), format!("{}k", rung.video_bitrate),
+        format!("-bufsize:v:{}", i), format!("{}k", rung.video_bitrate * 2),
+      ]);
+    }
+    if self.plan.audio_index.is_some() && !self.has_multiple_audio() {
+      args.extend(["-c:a", if self.plan.copy_audio { "copy" } else { "aac" }].map(String::from));
+      if !self.plan.copy_audio {
+        for (i, rung) in self.ladder.iter().enumerate() {
+          args.extend([format!("-b:a:{}", i), format!("{}k", rung.audio_bitrate)]);
+        }
       }
     }
+    args.extend(self.segments.keyframe_args());
 
-    Ok(duration)
+    args
+  }
+
+  fn var_stream_map(&self) -> String {
+    let multiple_audio = self.has_multiple_audio();
+    let mut entries: Vec<String> = self.video_variants()
+      .iter()
+      .enumerate()
+      .map(|(i, variant)| match self.plan.audio_index {
+        _ if multiple_audio => format!("v:{},agroup:audio,name:{}", i, variant),
+        Some(_) => format!("v:{},a:{},name:{}", i, i, variant),
+        None => format!("v:{},name:{}", i, variant),
+      })
+      .collect();
+    entries.extend(self.audio_variants()
+      .iter()
+      .enumerate()
+      .map(|(i, variant)| format!("a:{},agroup:audio,name:{}", i, variant)));
+
+    entries.join(" ")
+  }
+
+  fn uses_hardware(&self) -> bool {
+    !self.plan.copy_video && self.backend.is_hardware()
+  }
+
+  // Passing a resume point continues an interrupted transcode after its last
+  // complete segment instead of starting over.
+  fn spawn_transcode(&self, resume_point: Option<(usize, f64)>) -> Result<Child, std::io::Error> {
+    let mut args: Vec<String> = Vec::new();
+    if let Some((_, offset)) = resume_point {
+      args.extend(["-ss".to_string(), offset.to_string()]);
+    }
+    if !self.ladder.is_empty() {
+      args.extend(self.backend.device_args().into_iter().map(String::from));
+    } else if !self.plan.copy_video {
+      args.extend(self.backend.input_args().into_iter().map(String::from));
+    }
+    args.extend(["-i".to_string(), self.input_path.clone()]);
+    if !self.ladder.is_empty() {
+      args.extend(self.ladder_args());
+    } else if let Some(video_index) = self.plan.video_index {
+      args.extend(["-map".to_string(), format!("0:{}", video_index)]);
+      if self.plan.copy_video {
+        args.extend(["-c:v", "copy"].map(String::from));
+        if let Some(video_tag) = self.plan.video_tag {
+          args.extend(["-tag:v", video_tag].map(String::from));
+        }
+      } else {
+        args.extend(self.backend.output_args().into_iter().map(String::from));
+        args.extend(self.segments.keyframe_args());
+      }
+    }
+    if self.has_multiple_audio() {
+      for audio_index in &self.plan.audio_indexes {
+        args.extend(["-map".to_string(), format!("0:{}", audio_index)]);
+      }
+      args.extend(["-c:a", if self.plan.copy_audio { "copy" } else { "aac" }].map(String::from));
+    } else if let (true, Some(audio_index)) = (self.ladder.is_empty(), self.plan.audio_index) {
+      args.extend(["-map".to_string(), format!("0:{}", audio_index)]);
+      args.extend(["-c:a", if self.plan.copy_audio { "copy" } else { "aac" }].map(String::from));
+    }
+    args.extend([
+      "-hls_time".to_string(), self.segments.duration.to_string(),
+      "-hls_list_size".to_string(), "0".to_string(),
+    ]);
+    args.extend(self.segments.format.hls_args().into_iter().map(String::from));
+    if let Some((start_number, offset)) = resume_point {
+      args.extend([
+        "-start_number".to_string(), start_number.to_string(),
+        "-output_ts_offset".to_string(), offset.to_string(),
+      ]);
+      if !self.on_demand {
+        args.extend(["-hls_flags", "append_list"].map(String::from));
+      }
+    }
+    if self.on_demand {
+      // Segments only show up under their final name once fully written,
+      // which is what the server waits for.
+      args.extend(["-hls_flags", "temp_file"].map(String::from));
+      let _ = fs::remove_file(entry_file(&self.output_dir_name, ON_DEMAND_PLAYLIST));
+    }
+    if self.video_variants().is_empty() {
+      let output_playlist_path = if self.on_demand {
+        entry_file_str(&self.output_dir_name, ON_DEMAND_PLAYLIST)
+      } else {
+        self.playlist_path()
+      };
+      args.extend([
+        "-hls_segment_filename".to_string(), entry_file_str(&self.output_dir_name, &self.segments.file_pattern()),
+        output_playlist_path,
+      ]);
+    } else {
+      if !self.has_multiple_audio() {
+        args.extend(["-master_pl_name", "master.m3u8"].map(String::from));
+      }
+      args.extend([
+        "-var_stream_map".to_string(), self.var_stream_map(),
+        "-hls_segment_filename".to_string(), entry_file_str(&self.output_dir_name, &format!("%v/{}", self.segments.file_pattern())),
+        entry_file_str(&self.output_dir_name, "%v/playlist.m3u8"),
+      ]);
+    }
+    args.extend(["-progress", "pipe:1", "-nostats"].map(String::from));
+
+    let mut child = Command::new("ffmpeg")
+      .args(args)
+      .stdout(Stdio::piped())
+      .stderr(Stdio::piped())
+      .spawn()?;
+
+    if let Some(stdout) = child.stdout.take() {
+      let reporter = ProgressReporter::new(
+        self.window.clone(),
+        self.input_path.clone(),
+        self.duration,
+        resume_point.map_or(0.0, |(_, offset)| offset),
+      );
+      tokio::spawn(reporter.run(stdout));
+    }
+
+    Ok(child)
   }
 }
 
+// Keep reading so ffmpeg never blocks on a full stderr pipe.
+fn drain_stderr(mut reader: Lines<BufReader<ChildStderr>>) {
+  tokio::spawn(async move {
+    while let Ok(Some(_)) = reader.next_line().await {}
+  });
+}
+
 #[derive(Serialize)]
 pub struct ApiResponse {
   success: bool,
   message: String,
   playlist_url: String,
   duration: f64,
+  encoder: Option<EncoderBackend>,
+  transcode_mode: Option<TranscodeMode>,
+  playback_mode: PlaybackMode,
 }
 
-#[tauri::command]
-pub async fn generate_hls(input_path: String) -> Result<ApiResponse, String> {
-  let mut duration: f64 = 0.0;
-  {
-    let cache_map = CACHE_MAP.lock().unwrap();
-    let cache = cache_map.get(&input_path);
-    if cache.is_some() {
-      let cache = cache.unwrap();
-      let output_dir_name = cache.output_dir_name.clone();
-      duration = cache.duration.clone();
-      let output_playlist_path = "hls/".to_string() + &output_dir_name + "/playlist.m3u8";
-      drop(cache_map);
-
-      return Ok(ApiResponse {
-        duration,
-        success: true,
-        message: "HLS stream generated successfully".to_string(),
-        playlist_url: get_file_url(&output_playlist_path),
-      })
+#[derive(Serialize)]
+pub struct AudioTrackResponse {
+  success: bool,
+  message: String,
+  tracks: Vec<AudioTrack>,
+}
+
+#[derive(Serialize)]
+pub struct CancelResponse {
+  success: bool,
+  message: String,
+  cancelled: bool,
+}
+
+/// Extracts the audio speech recognition runs on. It is written under a
+/// temporary name and moved into place once complete, so a cancelled run
+/// never leaves a truncated `audio.aac` behind.
+fn spawn_audio_extract(
+  input_path: &str,
+  output_dir_name: &str,
+  audio_index: usize,
+  mut cancel: watch::Receiver<bool>,
+) -> Result<(), std::io::Error> {
+  let output_audio_path = entry_file(output_dir_name, "audio.aac");
+  if output_audio_path.is_file() {
+    return Ok(());
+  }
+  let temp_audio_path = entry_file(output_dir_name, "audio.aac.tmp");
+
+  let mut audio_extrac_cmd = Command::new("ffmpeg")
+    .args([
+      "-y",
+      "-i", input_path,
+      "-map", &format!("0:{}", audio_index),
+      "-c:a", "aac",
+      "-ar", "16000",
+      "-ac", "2",
+      "-f", "adts",
+    ])
+    .arg(&temp_audio_path)
+    .spawn()?;
+  attach_task(input_path, tokio::spawn(async move {
+    if let Some(status) = wait_or_kill(&mut audio_extrac_cmd, &mut cancel).await {
+      if status.success() {
+        let _ = fs::rename(&temp_audio_path, &output_audio_path);
+      }
     }
-    drop(cache_map);
+  }));
+
+  Ok(())
+}
+
+// Redoes the audio and embedded subtitle extraction of a finished entry
+// whose extraction was cancelled.
+async fn restore_extractions(input_path: &str, cache: &Cache) -> Result<(), String> {
+  let has_audio = entry_file(&cache.output_dir_name, "audio.aac").is_file();
+  let has_subtitles = cache.subtitles.iter()
+    .filter(|subtitle| subtitle.source == SubtitleSource::Embedded)
+    .all(|subtitle| entry_file(&cache.output_dir_name, &subtitle.file).is_file());
+  if (has_audio && has_subtitles) || is_job_running(input_path) {
+    return Ok(());
+  }
+  let media_info = probe(input_path).await.map_err(|err| err.to_string())?;
+  let plan = TranscodePlan::new(&media_info, cache.segment_format, &settings().audio_languages);
+
+  let cancel = register_job(input_path);
+  let mut extracted = extract_embedded_subtitles(input_path, &cache.output_dir_name, &cache.subtitles, cancel.clone());
+  if let (Ok(()), Some(audio_index)) = (&extracted, plan.audio_index) {
+    extracted = spawn_audio_extract(input_path, &cache.output_dir_name, audio_index, cancel);
   }
-  let output_dir_name = generate_dir_name(&input_path);
-  let output_playlist_path = "hls/".to_string() + &output_dir_name + "/playlist.m3u8";
+  remove_idle_job(input_path);
+  if let Err(err) = extracted {
+    cancel_job(input_path).await;
+    return Err(err.to_string());
+  }
+
+  Ok(())
+}
+
+// Stops the job. Segments written so far are kept for resuming, unless there
+// are none yet.
+async fn cancel_transcode(input_path: &str) -> bool {
+  let was_running = cancel_job(input_path).await;
+  if was_running {
+    let entry = CACHE_MAP.lock().unwrap()
+      .get(input_path)
+      .map(|cache| (
+        cache.output_dir_name.clone(),
+        cache.playlist_path(),
+        cache.playback_mode == PlaybackMode::Direct || cache.state == CacheState::Complete,
+      ));
+    // Without an entry the preparation is still running, it removes its
+    // directory itself once it sees the cancellation. A finished entry only
+    // loses its audio or subtitle extraction, which `restore_extractions`
+    // redoes when it is opened again.
+    if let Some((output_dir_name, output_playlist_path, false)) = entry {
+      if playlist::existing_segments(&output_playlist_path) == 0 {
+        cache_map_remove(input_path);
+        let _ = fs::remove_dir_all(entry_dir(&output_dir_name));
+      }
+    }
+  }
+
+  was_running
+}
+
+fn cached_response(input_path: &str) -> Option<ApiResponse> {
+  let cache_map = CACHE_MAP.lock().unwrap();
+  cache_map.get(input_path).map(|cache| {
+    ApiResponse {
+      duration: cache.duration,
+      success: true,
+      message: match cache.playback_mode {
+        PlaybackMode::Hls => "HLS stream generated successfully",
+        PlaybackMode::Direct => "Source file is played directly",
+      }.to_string(),
+      playlist_url: get_file_url(&cache.output_dir_name, &cache.media_file_name()),
+      encoder: cache.encoder,
+      transcode_mode: cache.transcode_mode,
+      playback_mode: cache.playback_mode,
+    }
+  })
+}
+
+fn audio_variant_name(position: usize) -> String {
+  format!("audio_{}", position)
+}
+
+// The audio streams the plan outputs, labelled by title, then language. With
+// several of them each gets its own rendition playlist.
+fn audio_tracks(media_info: &MediaInfo, plan: &TranscodePlan) -> Vec<AudioTrack> {
+  let has_renditions = plan.audio_indexes.len() > 1;
+  let mut names: Vec<String> = Vec::new();
+
+  plan.audio_indexes.iter()
+    .filter_map(|index| media_info.stream(*index))
+    .enumerate()
+    .map(|(position, stream)| {
+      let mut name = stream.title()
+        .or_else(|| stream.language())
+        .map(String::from)
+        .unwrap_or_else(|| format!("Track {}", position + 1));
+      if names.contains(&name) {
+        name = format!("{} ({})", name, position + 1);
+      }
+      names.push(name.clone());
+
+      AudioTrack {
+        index: stream.index,
+        name,
+        playlist: if has_renditions { Some(format!("{}/playlist.m3u8", audio_variant_name(position))) } else { None },
+        language: stream.language().map(String::from),
+        title: stream.title().map(String::from),
+        codec: stream.codec().to_string(),
+        channels: stream.channels,
+        default: Some(stream.index) == plan.audio_index,
+      }
+    })
+    .collect()
+}
+
+// Rungs of the configured ABR profile the source is tall enough for, tallest
+// first. A source below every rung still gets the smallest one.
+fn abr_ladder(source_height: Option<u32>) -> Vec<RenditionProfile> {
+  let settings = settings();
+  let mut ladder = settings.abr_profile.as_ref()
+    .and_then(|name| settings.abr_profiles.get(name).cloned())
+    .unwrap_or_default();
+  ladder.sort_by_key(|rung| Reverse(rung.height));
+
+  if let Some(source_height) = source_height {
+    let smallest = ladder.last().cloned();
+    ladder.retain(|rung| rung.height <= source_height);
+    if ladder.is_empty() {
+      ladder.extend(smallest);
+    }
+  }
+
+  ladder
+}
+
+async fn prepare_hls(window: Window, input_path: String) -> Result<(), String> {
+  if let CacheLookup::Stale = cache_map_lookup(&input_path) {
+    println!("source changed, discarding cached HLS for {}", input_path);
+    cancel_job(&input_path).await;
+    if let Some(output_dir_name) = cache_map_remove(&input_path) {
+      let _ = fs::remove_dir_all(entry_dir(&output_dir_name));
+    }
+  }
+
+  let direct_play = settings().direct_play;
+  let existing = CACHE_MAP.lock().unwrap().get(&input_path).cloned();
+  let mut resumable = None;
+  match existing {
+    Some(cache) if cache.playback_mode == PlaybackMode::Direct && !direct_play => {
+      cancel_job(&input_path).await;
+      cache_map_remove(&input_path);
+      let _ = fs::remove_dir_all(entry_dir(&cache.output_dir_name));
+    },
+    Some(cache) if cache.state == CacheState::Complete => return restore_extractions(&input_path, &cache).await,
+    Some(cache) if cache.state == CacheState::InProgress && is_job_running(&input_path) => return Ok(()),
+    Some(cache) => {
+      if cache.state == CacheState::InProgress && playlist::existing_segments(&cache.playlist_path()) > 0 {
+        resumable = Some(cache);
+      } else {
+        cache_map_remove(&input_path);
+        let _ = fs::remove_dir_all(entry_dir(&cache.output_dir_name));
+      }
+    },
+    None => (),
+  }
+
+  let media_info = probe(&input_path).await.map_err(|err| err.to_string())?;
+  let duration = media_info.duration();
+  let segments = settings().segment_options();
+  let segment_format = segments.format;
+  let mut plan = TranscodePlan::new(&media_info, segment_format, &settings().audio_languages);
+  let video = plan.video_index.and_then(|index| media_info.stream(index));
+  let ladder = if video.is_some() { abr_ladder(video.and_then(|stream| stream.height)) } else { Vec::new() };
+  if !ladder.is_empty() {
+    plan.copy_video = false;
+  }
+  // Only re-encoded output can be cut at arbitrary points.
+  let on_demand = settings().on_demand_seek
+    && ladder.is_empty()
+    && plan.audio_indexes.len() <= 1
+    && !plan.copy_video
+    && duration > 0.0;
+  let transcode_mode = plan.mode();
+  // A continued entry may live in a directory named after a path its file
+  // was moved from.
+  let output_dir_name = match &resumable {
+    Some(cache) => cache.output_dir_name.clone(),
+    None => new_dir_name(&input_path),
+  };
   let mut cmd = FFmpegCommand::new(
     input_path.clone(),
     output_dir_name.clone(),
+    best_backend().await,
+    plan,
+    ladder,
+    segments,
+    on_demand,
+    duration,
+    window,
+    register_job(&input_path),
   ).unwrap();
-  
-  fs::create_dir("hls/".to_owned() + &output_dir_name).unwrap();
+  let renditions = cmd.renditions(&media_info);
+  let audio_tracks = audio_tracks(&media_info, &cmd.plan);
+  // Generated subtitles of a continued entry are kept, embedded ones are
+  // listed afresh below.
+  let mut subtitles: Vec<_> = resumable.as_ref()
+    .map(|cache| cache.subtitles.clone())
+    .unwrap_or_default()
+    .into_iter()
+    .filter(|subtitle| subtitle.source != SubtitleSource::Embedded)
+    .collect();
+  subtitles.extend(embedded_subtitles(&media_info));
 
-  if let Ok(duration) = cmd.execute().await {
-    cache_map_insert(input_path.clone(), Cache {
-      duration,
-      output_dir_name,
-      original_file_path: input_path,
-    }).unwrap();
-    
-    return Ok(ApiResponse {
-      duration,
-      success: true,
-      message: "HLS stream generated successfully".to_string(),
-      playlist_url: get_file_url(&output_playlist_path),
-    })
+  // Output of a different ladder or segment layout can't be continued. An
+  // on-demand entry continues at its first gap, a linear one after its last
+  // listed segment.
+  let resume_point = match &resumable {
+    Some(cache) => {
+      let same_ladder = cache.renditions.iter().map(|rendition| &rendition.name)
+        .eq(renditions.iter().map(|rendition| &rendition.name))
+        && cache.audio_tracks.iter().map(|track| &track.playlist)
+          .eq(audio_tracks.iter().map(|track| &track.playlist));
+      let same_segments = cache.segment_format == segment_format
+        && cache.on_demand == on_demand
+        && (!on_demand || playlist::segment_files(&cache.playlist_path()) == cmd.segment_files());
+      if same_ladder && same_segments && on_demand {
+        match playlist::first_missing_segment(&cache.playlist_path(), 0) {
+          Some(index) => Some((index, index as f64 * cmd.segments.duration as f64)),
+          None => {
+            cancel_job(&input_path).await;
+            cache_map_set_state(&input_path, CacheState::Complete);
+            return Ok(());
+          },
+        }
+      } else if same_ladder && same_segments {
+        Some(playlist::completed_segments(&cache.playlist_path()))
+      } else {
+        cache_map_remove(&input_path);
+        let _ = fs::remove_dir_all(entry_dir(&cache.output_dir_name));
+        None
+      }
+    },
+    None => None,
+  };
+
+  // Output replacing a discarded entry goes into a new directory, the old
+  // one's URLs may still be cached by the webview.
+  let output_dir_name = match (&resumable, resume_point) {
+    (Some(_), None) => new_dir_name(&input_path),
+    _ => output_dir_name,
+  };
+  cmd.output_dir_name = output_dir_name.clone();
+
+  fs::create_dir_all(entry_dir(&output_dir_name)).map_err(|err| err.to_string())?;
+  if let Err(err) = extract_embedded_subtitles(&input_path, &output_dir_name, &subtitles, cmd.cancel.clone()) {
+    cancel_job(&input_path).await;
+    let _ = fs::remove_dir_all(entry_dir(&output_dir_name));
+    return Err(err.to_string());
   }
 
-  Ok(ApiResponse {
-    duration,
+  if resume_point.is_none() && direct_play && media_info.is_direct_playable(&input_path) {
+    if let Err(err) = cmd.extract_audio() {
+      cancel_job(&input_path).await;
+      let _ = fs::remove_dir_all(entry_dir(&output_dir_name));
+      return Err(err.to_string());
+    }
+
+    let mut cache = Cache::new(input_path.clone(), output_dir_name, duration).map_err(|err| err.to_string())?;
+    cache.codecs = [cmd.plan.video_index, cmd.plan.audio_index]
+      .iter()
+      .filter_map(|index| index.and_then(|index| media_info.stream(index)))
+      .map(|stream| stream.codec().to_string())
+      .collect();
+    cache.subtitles = subtitles;
+    cache.playback_mode = PlaybackMode::Direct;
+    cache.state = CacheState::Complete;
+    remove_idle_job(&input_path);
+
+    return cache_map_insert(input_path, cache).map_err(|err| err.to_string());
+  }
+
+  if cmd.has_multiple_audio() {
+    let master_playlist_path = entry_file_str(&output_dir_name, "master.m3u8");
+    if let Err(err) = playlist::write_master_playlist(&master_playlist_path, &renditions, &audio_tracks) {
+      cancel_job(&input_path).await;
+      let _ = fs::remove_dir_all(entry_dir(&output_dir_name));
+      return Err(err.to_string());
+    }
+  }
+
+  // The entry goes in before ffmpeg starts, a short stream copy can finish
+  // before `execute` returns and its outcome has to land on the entry.
+  let inserted = Cache::new(input_path.clone(), output_dir_name.clone(), duration)
+    .and_then(|mut cache| {
+      cache.codecs = [cmd.plan.video_index, cmd.plan.audio_index]
+        .iter()
+        .filter_map(|index| index.and_then(|index| media_info.stream(index)))
+        .map(|stream| stream.codec().to_string())
+        .collect();
+      cache.renditions = renditions;
+      cache.audio_tracks = audio_tracks;
+      cache.subtitles = subtitles;
+      if !cmd.video_variants().is_empty() {
+        cache.master_playlist = Some("master.m3u8".to_string());
+      }
+      cache.segment_format = segment_format;
+      cache.on_demand = cmd.on_demand;
+      cache.encoder = if cmd.plan.copy_video { None } else { Some(cmd.backend) };
+      cache.transcode_mode = Some(transcode_mode);
+      // The same source again, so the user's pin and the entry's history stay.
+      if let Some(resumable) = &resumable {
+        cache.pinned = resumable.pinned;
+        cache.created_at = resumable.created_at;
+        cache.last_accessed = resumable.last_accessed;
+      }
+      cache_map_insert(input_path.clone(), cache)
+    });
+  if let Err(err) = inserted {
+    cancel_job(&input_path).await;
+    let _ = fs::remove_dir_all(entry_dir(&output_dir_name));
+    return Err(err.to_string());
+  }
+
+  match cmd.execute(resume_point).await.map_err(|err| err.to_string()) {
+    Ok(()) => Ok(()),
+    Err(message) => {
+      cancel_job(&input_path).await;
+      cache_map_remove(&input_path);
+      let _ = fs::remove_dir_all(entry_dir(&output_dir_name));
+
+      Err(message)
+    },
+  }
+}
+
+#[tauri::command]
+pub async fn generate_hls(window: Window, input_path: String, preempt: Option<bool>) -> Result<ApiResponse, String> {
+  set_entry_open(&input_path, true);
+  if preempt.unwrap_or(false) {
+    for running_input_path in running_jobs() {
+      if running_input_path != input_path {
+        cancel_transcode(&running_input_path).await;
+      }
+    }
+  }
+
+  let result = prepare_once(&input_path, || prepare_hls(window, input_path.clone())).await
+    .and_then(|()| cached_response(&input_path).ok_or_else(|| "HLS transcode cancelled".to_string()));
+
+  match result {
+    Ok(response) => Ok(response),
+    Err(message) => {
+      set_entry_open(&input_path, false);
+      Ok(ApiResponse {
+        duration: 0.0,
+        success: false,
+        message,
+        playlist_url: String::new(),
+        encoder: None,
+        transcode_mode: None,
+        playback_mode: PlaybackMode::Hls,
+      })
+    },
+  }
+}
+
+#[tauri::command]
+pub async fn cancel_hls(input_path: String) -> Result<CancelResponse, String> {
+  set_entry_open(&input_path, false);
+  let cancelled = cancel_transcode(&input_path).await;
+
+  Ok(CancelResponse {
+    cancelled,
+    success: true,
+    message: if cancelled { "HLS transcode cancelled" } else { "No running HLS transcode" }.to_string(),
+  })
+}
+
+/// Audio tracks of the input as the player sees them: from the cache entry if
+/// there is one, otherwise as a transcode with the current settings would
+/// output them.
+#[tauri::command]
+pub async fn list_audio_tracks(input_path: String) -> Result<AudioTrackResponse, String> {
+  let cached = CACHE_MAP.lock().unwrap()
+    .get(&input_path)
+    .filter(|cache| cache.playback_mode == PlaybackMode::Hls && !cache.audio_tracks.is_empty())
+    .map(|cache| cache.audio_tracks.clone());
+  if let Some(tracks) = cached {
+    return Ok(AudioTrackResponse { success: true, message: "Audio tracks listed".to_string(), tracks });
+  }
+
+  let media_info = match probe(&input_path).await.map_err(|err| err.to_string()) {
+    Ok(media_info) => media_info,
+    Err(message) => return Ok(AudioTrackResponse { success: false, message, tracks: Vec::new() }),
+  };
+  let settings = settings();
+  let plan = TranscodePlan::new(&media_info, settings.segment_format, &settings.audio_languages);
+
+  Ok(AudioTrackResponse {
     success: true,
-    message: "HLS stream generated successfully".to_string(),
-    playlist_url: get_file_url(&output_playlist_path),
+    message: "Audio tracks listed".to_string(),
+    tracks: audio_tracks(&media_info, &plan),
   })
 }
+
+/// Called by the server for a segment of an on-demand entry that isn't written
+/// yet. Asks the transcode to produce it and waits until it has.
+pub async fn wait_for_segment(output_dir_name: &str, file_name: &str) -> bool {
+  let (input_path, playlist_path) = match on_demand_by_dir(output_dir_name) {
+    Some(entry) => entry,
+    None => return false,
+  };
+  let index = match playlist::segment_files(&playlist_path).iter().position(|file| file == file_name) {
+    Some(index) => index,
+    None => return false,
+  };
+  if !request_segment(&input_path, index) {
+    return false;
+  }
+
+  let segment_path = entry_file(output_dir_name, file_name);
+  let deadline = Instant::now() + SEGMENT_WAIT_TIMEOUT;
+  while Instant::now() < deadline && is_job_running(&input_path) {
+    if segment_path.is_file() {
+      return true;
+    }
+    tokio::time::sleep(Duration::from_millis(250)).await;
+  }
+
+  segment_path.is_file()
+}
@@ -0,0 +1,132 @@
+use std::{
+  sync::Mutex,
+  process::ExitStatus,
+  collections::HashMap,
+};
+use lazy_static::lazy_static;
+use tokio::{
+  process::Child,
+  sync::watch,
+  task::JoinHandle,
+};
+
+/// A running transcode. Every task that owns one of its ffmpeg children is
+/// attached here so cancelling can wait for the children to actually exit.
+struct Job {
+  cancel: watch::Sender<bool>,
+  tasks: Vec<JoinHandle<()>>,
+  /// Set for on-demand transcodes, which restart at segments asked for here.
+  seek: Option<watch::Sender<usize>>,
+}
+
+impl Job {
+  // A job without tasks is still waiting for ffmpeg's first playlist write.
+  fn is_running(&self) -> bool {
+    self.tasks.is_empty() || self.tasks.iter().any(|task| !task.is_finished())
+  }
+}
+
+lazy_static! {
+  static ref JOBS: Mutex<HashMap<String, Job>> = Mutex::new(HashMap::new());
+}
+
+pub fn register_job(input_path: &str) -> watch::Receiver<bool> {
+  let (cancel, cancel_rx) = watch::channel(false);
+  let mut jobs = JOBS.lock().unwrap();
+  jobs.insert(input_path.to_string(), Job { cancel, tasks: Vec::new(), seek: None });
+  drop(jobs);
+
+  cancel_rx
+}
+
+pub fn attach_task(input_path: &str, task: JoinHandle<()>) {
+  let mut jobs = JOBS.lock().unwrap();
+  if let Some(job) = jobs.get_mut(input_path) {
+    job.tasks.push(task);
+  }
+  drop(jobs);
+}
+
+/// Drops the job if nothing got attached to it, for a preparation that turned
+/// out to need no ffmpeg run. Such a job would otherwise count as running.
+pub fn remove_idle_job(input_path: &str) {
+  let mut jobs = JOBS.lock().unwrap();
+  if jobs.get(input_path).map_or(false, |job| job.tasks.is_empty()) {
+    jobs.remove(input_path);
+  }
+  drop(jobs);
+}
+
+pub fn attach_seek(input_path: &str, seek: watch::Sender<usize>) {
+  let mut jobs = JOBS.lock().unwrap();
+  if let Some(job) = jobs.get_mut(input_path) {
+    job.seek = Some(seek);
+  }
+  drop(jobs);
+}
+
+/// Asks the job to produce the segment at `index`. Returns false when there is
+/// no running on-demand job to ask.
+pub fn request_segment(input_path: &str, index: usize) -> bool {
+  let jobs = JOBS.lock().unwrap();
+  match jobs.get(input_path) {
+    Some(job) if job.is_running() => job.seek.as_ref().map_or(false, |seek| seek.send(index).is_ok()),
+    _ => false,
+  }
+}
+
+pub fn is_job_running(input_path: &str) -> bool {
+  let jobs = JOBS.lock().unwrap();
+  jobs.get(input_path).map_or(false, Job::is_running)
+}
+
+/// Input paths of every job that still has a live ffmpeg child.
+pub fn running_jobs() -> Vec<String> {
+  let jobs = JOBS.lock().unwrap();
+  jobs.iter()
+    .filter(|(_, job)| job.is_running())
+    .map(|(input_path, _)| input_path.clone())
+    .collect()
+}
+
+/// Kills the job's children and waits for them to exit. Returns whether the
+/// job was still running, i.e. whether its output is incomplete.
+pub async fn cancel_job(input_path: &str) -> bool {
+  let job = {
+    let mut jobs = JOBS.lock().unwrap();
+    jobs.remove(input_path)
+  };
+
+  match job {
+    Some(job) => {
+      let was_running = job.is_running();
+      let _ = job.cancel.send(true);
+      for task in job.tasks {
+        let _ = task.await;
+      }
+      was_running
+    },
+    None => false,
+  }
+}
+
+/// Resolves once the job has been cancelled.
+pub async fn cancelled(cancel: &mut watch::Receiver<bool>) {
+  while !*cancel.borrow() {
+    if cancel.changed().await.is_err() {
+      std::future::pending::<()>().await;
+    }
+  }
+}
+
+/// Waits for the child to exit, killing it if the job is cancelled first.
+/// Returns `None` when the child was killed.
+pub async fn wait_or_kill(child: &mut Child, cancel: &mut watch::Receiver<bool>) -> Option<ExitStatus> {
+  tokio::select! {
+    status = child.wait() => status.ok(),
+    _ = cancelled(cancel) => {
+      let _ = child.kill().await;
+      None
+    },
+  }
+}
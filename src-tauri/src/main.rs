@@ -2,54 +2,95 @@
 #![cfg_attr(not(debug_assertions), windows_subsystem = "windows")]
 
 mod utils;
+mod asr;
+mod asr_bytedance;
+mod asr_local;
 mod hls_command;
 mod server;
 mod cache;
 mod subtitle;
+mod subtitle_convert;
+mod subtitle_tracks;
+mod encoder;
+mod probe;
+mod progress;
+mod jobs;
+mod playlist;
+mod settings;
+mod paths;
 
 use crate::{
   utils::set_window_shadow,
   // hls::generate_hls
-  hls_command::generate_hls,
-  server::{serve_hls, SERVER_ADDRESS},
-  cache::init_hashmap,
+  hls_command::{generate_hls, cancel_hls, list_audio_tracks},
+  server::{serve_hls, SERVER_ADDRESS, ALLOWED_ORIGINS},
+  cache::{
+    init_hashmap,
+    evict_cache,
+    pin_cache_entry,
+    list_cache,
+    get_cache_entry,
+    delete_cache_entry,
+    clear_cache,
+  },
+  settings::{init_settings, get_settings, set_settings},
+  paths::init_cache_root,
   subtitle::generate_subtitle,
+  subtitle_tracks::{list_subtitles, set_subtitle_encoding},
+  encoder::available_backends,
 };
-use std::fs;
-use actix_web::{web, App, HttpServer};
+use actix_web::{guard, web, App, HttpServer};
 use actix_cors::Cors;
 
 // mod hls;
 
-fn dir_exists(dir_name: &str) -> bool {
-  fs::metadata(dir_name).is_ok()
-}
-
 fn main() {
   tauri::Builder::default()
   .setup(|app| {
     set_window_shadow(app);
-    if !dir_exists("hls") {
-      fs::create_dir("hls").unwrap();
-    }
-    init_hashmap("hls")?;
+    init_settings();
+    init_cache_root()?;
+    init_hashmap()?;
+    evict_cache();
+    tauri::async_runtime::spawn(async {
+      available_backends().await;
+    });
     
     tauri::async_runtime::spawn(
       HttpServer::new(move || {
-        let cors = Cors::default()
-          .allow_any_origin()
+        let cors = ALLOWED_ORIGINS
+          .iter()
+          .fold(Cors::default(), |cors, origin| cors.allowed_origin(origin))
+          .allowed_methods(["GET", "HEAD"])
           .allow_any_header()
-          .allow_any_method();
+          .expose_headers(["Accept-Ranges", "Content-Range", "Content-Length", "ETag"]);
         App::new()
           .wrap(cors)
-          .route("/{filename:.+}", web::get().to(serve_hls))
+          .route(
+            "/{token}/{filename:.+}",
+            web::route().guard(guard::Any(guard::Get()).or(guard::Head())).to(serve_hls),
+          )
       })
       .bind(SERVER_ADDRESS)?
       .run(),
     );
     Ok(())
   })
-  .invoke_handler(tauri::generate_handler![generate_hls, generate_subtitle])
+  .invoke_handler(tauri::generate_handler![
+    generate_hls,
+    cancel_hls,
+    list_audio_tracks,
+    generate_subtitle,
+    list_subtitles,
+    set_subtitle_encoding,
+    get_settings,
+    set_settings,
+    pin_cache_entry,
+    list_cache,
+    get_cache_entry,
+    delete_cache_entry,
+    clear_cache,
+  ])
   .run(tauri::generate_context!())
   .expect("error while running tauri application");
 }
@@ -0,0 +1,105 @@
+use std::{
+  fs,
+  path::{Path, PathBuf},
+  sync::RwLock,
+};
+use lazy_static::lazy_static;
+use crate::settings::{settings, APP_DIR_NAME};
+
+// Where entries were written before the cache root could be configured,
+// relative to the working directory.
+const LEGACY_CACHE_ROOT: &str = "hls";
+
+lazy_static! {
+  static ref CACHE_ROOT: RwLock<PathBuf> = RwLock::new(default_cache_root());
+}
+
+/// `$XDG_CACHE_HOME/my-player` on Linux, the platform cache directory elsewhere.
+pub fn default_cache_root() -> PathBuf {
+  tauri::api::path::cache_dir()
+    .map(|dir| dir.join(APP_DIR_NAME))
+    .unwrap_or_else(|| PathBuf::from(LEGACY_CACHE_ROOT))
+}
+
+/// Resolves the cache root from the settings and makes sure it exists. Called
+/// once on startup, so a changed `cache_dir` setting applies on the next launch.
+pub fn init_cache_root() -> Result<PathBuf, std::io::Error> {
+  let root = settings().cache_dir
+    .map(PathBuf::from)
+    .unwrap_or_else(default_cache_root);
+  fs::create_dir_all(&root)?;
+  migrate_legacy_root(&root);
+  *CACHE_ROOT.write().unwrap() = root.clone();
+
+  Ok(root)
+}
+
+// Moves the entries of the old `hls/` directory into the cache root, where
+// `init_hashmap` picks them up like any other. The old directory is removed
+// once empty, so this only does work on the first start after upgrading.
+fn migrate_legacy_root(root: &Path) {
+  let legacy_root = Path::new(LEGACY_CACHE_ROOT);
+  let needs_migration = match (legacy_root.canonicalize(), root.canonicalize()) {
+    (Ok(legacy_root), Ok(root)) => legacy_root != root,
+    _ => false,
+  };
+  if !needs_migration {
+    return;
+  }
+
+  let entries = match fs::read_dir(legacy_root) {
+    Ok(entries) => entries,
+    Err(_) => return,
+  };
+  for entry in entries.filter_map(|entry| entry.ok()).filter(|entry| entry.path().is_dir()) {
+    let target = root.join(entry.file_name());
+    if target.exists() {
+      println!("not moving cache entry {}, {} already exists", entry.path().display(), target.display());
+      continue;
+    }
+    // A rename can't cross file systems, copying can.
+    let moved = fs::rename(entry.path(), &target).or_else(|_| {
+      copy_dir(&entry.path(), &target).map_err(|err| {
+        let _ = fs::remove_dir_all(&target);
+        err
+      })?;
+      fs::remove_dir_all(entry.path())
+    });
+    if let Err(err) = moved {
+      println!("failed to move cache entry {}: {}", entry.path().display(), err);
+    }
+  }
+  let _ = fs::remove_dir(legacy_root);
+}
+
+fn copy_dir(from: &Path, to: &Path) -> Result<(), std::io::Error> {
+  fs::create_dir(to)?;
+  for entry in fs::read_dir(from)? {
+    let entry = entry?;
+    let target = to.join(entry.file_name());
+    if entry.file_type()?.is_dir() {
+      copy_dir(&entry.path(), &target)?;
+    } else {
+      fs::copy(entry.path(), target)?;
+    }
+  }
+
+  Ok(())
+}
+
+pub fn cache_root() -> PathBuf {
+  CACHE_ROOT.read().unwrap().clone()
+}
+
+pub fn entry_dir(output_dir_name: &str) -> PathBuf {
+  cache_root().join(output_dir_name)
+}
+
+pub fn entry_file(output_dir_name: &str, file_name: &str) -> PathBuf {
+  entry_dir(output_dir_name).join(file_name)
+}
+
+/// `entry_file` as a string, for ffmpeg arguments and the playlist helpers.
+pub fn entry_file_str(output_dir_name: &str, file_name: &str) -> String {
+  entry_file(output_dir_name, file_name).to_string_lossy().into_owned()
+}
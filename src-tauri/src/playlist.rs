@@ -0,0 +1,235 @@
+use std::{
+  fs,
+  path::Path,
+};
+use serde::{Serialize, Deserialize};
+use crate::cache::{AudioTrack, Rendition};
+
+// Used for a copied video stream that reports no bit rate of its own.
+const FALLBACK_BANDWIDTH: u64 = 5_000_000;
+
+/// Container of the HLS media segments.
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum SegmentFormat {
+  /// MPEG-TS `.ts` segments.
+  Ts,
+  /// Fragmented MP4: an `init.mp4` per rendition followed by `.m4s` segments.
+  Fmp4,
+}
+
+impl Default for SegmentFormat {
+  fn default() -> Self {
+    SegmentFormat::Ts
+  }
+}
+
+/// How the output is cut into segments.
+#[derive(Clone, Debug)]
+pub struct SegmentOptions {
+  pub format: SegmentFormat,
+  /// Target segment length in seconds.
+  pub duration: u32,
+  /// File name pattern without the extension, e.g. `seg_%05d`.
+  pub name: String,
+}
+
+impl SegmentFormat {
+  pub fn extension(&self) -> &'static str {
+    match self {
+      SegmentFormat::Ts => "ts",
+      SegmentFormat::Fmp4 => "m4s",
+    }
+  }
+
+  /// Muxer arguments selecting the container.
+  pub fn hls_args(&self) -> Vec<&'static str> {
+    match self {
+      SegmentFormat::Ts => vec![],
+      SegmentFormat::Fmp4 => vec!["-hls_segment_type", "fmp4", "-hls_fmp4_init_filename", "init.mp4"],
+    }
+  }
+}
+
+impl SegmentOptions {
+  pub fn file_pattern(&self) -> String {
+    format!("{}.{}", self.name, self.format.extension())
+  }
+
+  /// Number of segments `duration` seconds are cut into.
+  pub fn count(&self, duration: f64) -> usize {
+    ((duration / self.duration as f64).ceil() as usize).max(1)
+  }
+
+  /// The file ffmpeg writes for segment `index`, expanding the `%d` or `%0Nd`.
+  pub fn file_name(&self, index: usize) -> String {
+    let (prefix, rest) = self.name.split_once('%').unwrap_or((&self.name, "d"));
+    let (width, suffix) = rest.split_once('d').unwrap_or(("", ""));
+    let width = width.parse::<usize>().unwrap_or(0);
+
+    format!("{}{:0width$}{}.{}", prefix, index, suffix, self.format.extension(), width = width)
+  }
+
+  /// Forces a keyframe on every segment boundary, otherwise re-encoded
+  /// segments only end on the encoder's own keyframes and drift in length.
+  pub fn keyframe_args(&self) -> Vec<String> {
+    vec!["-force_key_frames".to_string(), format!("expr:gte(t,n_forced*{})", self.duration)]
+  }
+}
+
+/// Whether ffmpeg finished the playlist, i.e. wrote `#EXT-X-ENDLIST`.
+pub fn is_finished(playlist_path: &str) -> bool {
+  fs::read_to_string(playlist_path)
+    .map(|content| content.lines().any(|line| line.trim() == "#EXT-X-ENDLIST"))
+    .unwrap_or(false)
+}
+
+/// Number of segments listed in the playlist and the media time they cover.
+/// ffmpeg only lists a segment once it has been fully written.
+pub fn completed_segments(playlist_path: &str) -> (usize, f64) {
+  let content = fs::read_to_string(playlist_path).unwrap_or_default();
+
+  let mut count = 0;
+  let mut offset = 0.0;
+  for line in content.lines() {
+    if let Some(info) = line.strip_prefix("#EXTINF:") {
+      count += 1;
+      offset += info.split(',').next().and_then(|d| d.trim().parse::<f64>().ok()).unwrap_or(0.0);
+    }
+  }
+
+  (count, offset)
+}
+
+/// Makes sure `#EXT-X-TARGETDURATION` covers the longest segment once rounded,
+/// as the spec requires. Segments cut on sparse source keyframes or appended
+/// on resume can outgrow it; a too small value is raised to the required one,
+/// which is returned.
+pub fn fix_target_duration(playlist_path: &str) -> Result<Option<u64>, std::io::Error> {
+  let content = fs::read_to_string(playlist_path)?;
+
+  let mut target_duration = None;
+  let mut longest: f64 = 0.0;
+  for line in content.lines() {
+    if let Some(value) = line.strip_prefix("#EXT-X-TARGETDURATION:") {
+      target_duration = value.trim().parse::<u64>().ok();
+    } else if let Some(info) = line.strip_prefix("#EXTINF:") {
+      let duration = info.split(',').next().and_then(|d| d.trim().parse::<f64>().ok()).unwrap_or(0.0);
+      longest = longest.max(duration);
+    }
+  }
+
+  let required = longest.round() as u64;
+  match target_duration {
+    Some(target_duration) if target_duration < required => {
+      let fixed: Vec<String> = content
+        .lines()
+        .map(|line| if line.starts_with("#EXT-X-TARGETDURATION:") {
+          format!("#EXT-X-TARGETDURATION:{}", required)
+        } else {
+          line.to_string()
+        })
+        .collect();
+      let temp_path = playlist_path.to_string() + ".tmp";
+      fs::write(&temp_path, fixed.join("\n") + "\n")?;
+      fs::rename(temp_path, playlist_path)?;
+
+      Ok(Some(required))
+    },
+    _ => Ok(None),
+  }
+}
+
+/// Writes a complete VOD playlist of fixed-length segments covering
+/// `duration`, before any of them exist, so the player can seek anywhere.
+pub fn write_vod_playlist(playlist_path: &str, segments: &SegmentOptions, duration: f64) -> Result<(), std::io::Error> {
+  let segment_duration = segments.duration as f64;
+  let count = segments.count(duration);
+
+  let mut lines = vec![
+    "#EXTM3U".to_string(),
+    format!("#EXT-X-VERSION:{}", if segments.format == SegmentFormat::Fmp4 { 7 } else { 3 }),
+    format!("#EXT-X-TARGETDURATION:{}", segments.duration),
+    "#EXT-X-MEDIA-SEQUENCE:0".to_string(),
+    "#EXT-X-PLAYLIST-TYPE:VOD".to_string(),
+  ];
+  if segments.format == SegmentFormat::Fmp4 {
+    lines.push("#EXT-X-MAP:URI=\"init.mp4\"".to_string());
+  }
+  for index in 0..count {
+    let length = segment_duration.min(duration - index as f64 * segment_duration).max(0.0);
+    lines.push(format!("#EXTINF:{:.6},", length));
+    lines.push(segments.file_name(index));
+  }
+  lines.push("#EXT-X-ENDLIST".to_string());
+
+  let temp_path = playlist_path.to_string() + ".tmp";
+  fs::write(&temp_path, lines.join("\n") + "\n")?;
+  fs::rename(temp_path, playlist_path)
+}
+
+/// Segment file names in playlist order.
+pub fn segment_files(playlist_path: &str) -> Vec<String> {
+  fs::read_to_string(playlist_path)
+    .unwrap_or_default()
+    .lines()
+    .map(str::trim)
+    .filter(|line| !line.is_empty() && !line.starts_with('#'))
+    .map(String::from)
+    .collect()
+}
+
+/// Index of the first segment from `from` on whose file isn't written yet.
+pub fn first_missing_segment(playlist_path: &str, from: usize) -> Option<usize> {
+  let dir = Path::new(playlist_path).parent().unwrap_or_else(|| Path::new(""));
+  segment_files(playlist_path)
+    .iter()
+    .enumerate()
+    .skip(from)
+    .find(|(_, file)| !dir.join(file).is_file())
+    .map(|(index, _)| index)
+}
+
+/// Number of listed segments whose file is written.
+pub fn existing_segments(playlist_path: &str) -> usize {
+  let dir = Path::new(playlist_path).parent().unwrap_or_else(|| Path::new(""));
+  segment_files(playlist_path)
+    .iter()
+    .filter(|file| dir.join(file).is_file())
+    .count()
+}
+
+/// Writes the master playlist for video renditions that share a group of
+/// alternate audio renditions.
+pub fn write_master_playlist(playlist_path: &str, renditions: &[Rendition], audio_tracks: &[AudioTrack]) -> Result<(), std::io::Error> {
+  let mut lines = vec!["#EXTM3U".to_string(), "#EXT-X-VERSION:3".to_string()];
+  for track in audio_tracks {
+    let uri = match &track.playlist {
+      Some(playlist) => playlist,
+      None => continue,
+    };
+    let mut media = format!("#EXT-X-MEDIA:TYPE=AUDIO,GROUP-ID=\"audio\",NAME=\"{}\"", track.name.replace('"', "'"));
+    if let Some(language) = &track.language {
+      media += &format!(",LANGUAGE=\"{}\"", language);
+    }
+    media += &format!(
+      ",DEFAULT={},AUTOSELECT=YES,URI=\"{}\"",
+      if track.default { "YES" } else { "NO" },
+      uri,
+    );
+    lines.push(media);
+  }
+  for rendition in renditions {
+    let mut stream_info = format!("#EXT-X-STREAM-INF:BANDWIDTH={}", rendition.bandwidth.unwrap_or(FALLBACK_BANDWIDTH));
+    if let (Some(width), Some(height)) = (rendition.width, rendition.height) {
+      stream_info += &format!(",RESOLUTION={}x{}", width, height);
+    }
+    stream_info += ",AUDIO=\"audio\"";
+    lines.push(stream_info);
+    lines.push(rendition.playlist.clone());
+  }
+
+  let temp_path = playlist_path.to_string() + ".tmp";
+  fs::write(&temp_path, lines.join("\n") + "\n")?;
+  fs::rename(temp_path, playlist_path)
+}
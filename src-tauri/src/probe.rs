@@ -0,0 +1,242 @@
+use serde::{Serialize, Deserialize};
+use std::{
+  path::Path,
+  collections::HashMap,
+};
+use tokio::process::Command;
+use crate::playlist::SegmentFormat;
+
+#[derive(Deserialize, Clone, Debug)]
+pub struct MediaInfo {
+  pub format: FormatInfo,
+  #[serde(default)]
+  pub streams: Vec<StreamInfo>,
+}
+
+#[derive(Deserialize, Clone, Debug)]
+pub struct FormatInfo {
+  pub format_name: String,
+  pub duration: Option<String>,
+  pub bit_rate: Option<String>,
+}
+
+#[derive(Deserialize, Clone, Debug)]
+pub struct StreamInfo {
+  pub index: usize,
+  pub codec_type: String,
+  pub codec_name: Option<String>,
+  pub profile: Option<String>,
+  pub pix_fmt: Option<String>,
+  pub width: Option<u32>,
+  pub height: Option<u32>,
+  pub channels: Option<u32>,
+  pub bit_rate: Option<String>,
+  pub avg_frame_rate: Option<String>,
+  #[serde(default)]
+  pub disposition: HashMap<String, u8>,
+  #[serde(default)]
+  pub tags: HashMap<String, String>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum TranscodeMode {
+  Copy,
+  Audio,
+  Video,
+}
+
+/// Whether the webview plays the source file itself or an HLS stream made from it.
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum PlaybackMode {
+  Hls,
+  Direct,
+}
+
+impl Default for PlaybackMode {
+  fn default() -> Self {
+    PlaybackMode::Hls
+  }
+}
+
+/// Which input streams go into the HLS output and whether each can be copied.
+#[derive(Clone, Debug)]
+pub struct TranscodePlan {
+  pub video_index: Option<usize>,
+  /// The default audio stream, used on its own unless there are several.
+  pub audio_index: Option<usize>,
+  /// Every audio stream to output, in source order.
+  pub audio_indexes: Vec<usize>,
+  pub copy_video: bool,
+  pub copy_audio: bool,
+  /// Sample entry tag for copied video, `hvc1` so Apple players accept HEVC.
+  pub video_tag: Option<&'static str>,
+}
+
+impl MediaInfo {
+  pub fn duration(&self) -> f64 {
+    self.format.duration
+      .as_deref()
+      .and_then(|duration| duration.parse::<f64>().ok())
+      .unwrap_or(0.0)
+  }
+
+  pub fn stream(&self, index: usize) -> Option<&StreamInfo> {
+    self.streams.iter().find(|stream| stream.index == index)
+  }
+
+  /// Whether the webview can play the file as is: MP4 with H.264 and AAC/MP3,
+  /// or WebM with VP8/VP9/AV1 and Opus/Vorbis.
+  pub fn is_direct_playable(&self, input_path: &str) -> bool {
+    let extension = Path::new(input_path)
+      .extension()
+      .map(|extension| extension.to_string_lossy().to_ascii_lowercase())
+      .unwrap_or_default();
+    let video = self.video_stream();
+    let audio = self.audio_stream();
+    if video.is_none() && audio.is_none() {
+      return false;
+    }
+
+    match extension.as_str() {
+      "mp4" | "m4v" | "mov" => {
+        self.format.format_name.contains("mp4")
+          && video.map_or(true, StreamInfo::is_hls_video)
+          && audio.map_or(true, StreamInfo::is_hls_audio)
+      },
+      "webm" => {
+        self.format.format_name.contains("webm")
+          && video.map_or(true, |stream| matches!(stream.codec(), "vp8" | "vp9" | "av1"))
+          && audio.map_or(true, |stream| matches!(stream.codec(), "opus" | "vorbis"))
+      },
+      _ => false,
+    }
+  }
+
+  pub fn video_stream(&self) -> Option<&StreamInfo> {
+    self.streams
+      .iter()
+      .find(|stream| stream.codec_type == "video" && !stream.is_attached_pic())
+  }
+
+  pub fn audio_streams(&self) -> impl Iterator<Item = &StreamInfo> {
+    self.streams.iter().filter(|stream| stream.codec_type == "audio")
+  }
+
+  /// Bits per second of the whole file, for streams that don't report their own.
+  pub fn bit_rate(&self) -> Option<u64> {
+    self.format.bit_rate.as_deref().and_then(|bit_rate| bit_rate.parse().ok())
+  }
+
+  pub fn audio_stream(&self) -> Option<&StreamInfo> {
+    self.streams
+      .iter()
+      .find(|stream| stream.codec_type == "audio" && stream.disposition.get("default") == Some(&1))
+      .or_else(|| self.streams.iter().find(|stream| stream.codec_type == "audio"))
+  }
+}
+
+impl StreamInfo {
+  pub fn codec(&self) -> &str {
+    self.codec_name.as_deref().unwrap_or("")
+  }
+
+  pub fn language(&self) -> Option<&str> {
+    self.tags.get("language").map(String::as_str).filter(|language| *language != "und")
+  }
+
+  pub fn title(&self) -> Option<&str> {
+    self.tags.get("title").map(String::as_str)
+  }
+
+  pub fn bit_rate(&self) -> Option<u64> {
+    self.bit_rate.as_deref().and_then(|bit_rate| bit_rate.parse().ok())
+  }
+
+  /// Frames per second, from ffprobe's "24000/1001" style fraction.
+  pub fn frame_rate(&self) -> Option<f64> {
+    let (numerator, denominator) = self.avg_frame_rate.as_deref()?.split_once('/')?;
+    let (numerator, denominator) = (numerator.parse::<f64>().ok()?, denominator.parse::<f64>().ok()?);
+    if numerator > 0.0 && denominator > 0.0 { Some(numerator / denominator) } else { None }
+  }
+
+  // Cover art in MP3/MKV files shows up as a single frame video stream.
+  fn is_attached_pic(&self) -> bool {
+    self.disposition.get("attached_pic") == Some(&1)
+  }
+
+  fn is_hls_video(&self) -> bool {
+    let is_8bit_420 = matches!(self.pix_fmt.as_deref(), Some("yuv420p") | Some("yuvj420p"));
+    let is_supported_profile = !matches!(self.profile.as_deref(), Some("High 10") | Some("High 4:2:2") | Some("High 4:4:4 Predictive"));
+
+    self.codec() == "h264" && is_8bit_420 && is_supported_profile
+  }
+
+  // HEVC and AV1 can only be carried in fMP4 segments.
+  fn is_fmp4_video(&self) -> bool {
+    self.is_hls_video() || matches!(self.codec(), "hevc" | "av1")
+  }
+
+  fn is_hls_audio(&self) -> bool {
+    matches!(self.codec(), "aac" | "mp3")
+  }
+}
+
+impl TranscodePlan {
+  /// `audio_languages` picks the audio streams to keep by their language tag.
+  /// Empty, or matching none of them, keeps every stream.
+  pub fn new(info: &MediaInfo, segment_format: SegmentFormat, audio_languages: &[String]) -> Self {
+    let video = info.video_stream();
+    let audio = info.audio_stream();
+    let mut audio_streams: Vec<&StreamInfo> = info.audio_streams()
+      .filter(|stream| stream.language().map_or(false, |language| audio_languages.iter().any(|wanted| wanted == language)))
+      .collect();
+    if audio_streams.is_empty() {
+      audio_streams = info.audio_streams().collect();
+    }
+    let audio = audio.filter(|audio| audio_streams.iter().any(|stream| stream.index == audio.index))
+      .or_else(|| audio_streams.first().copied());
+    let copy_video = match segment_format {
+      SegmentFormat::Ts => video.map_or(true, StreamInfo::is_hls_video),
+      SegmentFormat::Fmp4 => video.map_or(true, StreamInfo::is_fmp4_video),
+    };
+    let is_hevc = video.map_or(false, |stream| stream.codec() == "hevc");
+
+    Self {
+      video_index: video.map(|stream| stream.index),
+      audio_index: audio.map(|stream| stream.index),
+      audio_indexes: audio_streams.iter().map(|stream| stream.index).collect(),
+      copy_video,
+      copy_audio: audio_streams.iter().all(|stream| stream.is_hls_audio()),
+      video_tag: if copy_video && is_hevc { Some("hvc1") } else { None },
+    }
+  }
+
+  pub fn mode(&self) -> TranscodeMode {
+    match (self.copy_video, self.copy_audio) {
+      (true, true) => TranscodeMode::Copy,
+      (true, false) => TranscodeMode::Audio,
+      (false, _) => TranscodeMode::Video,
+    }
+  }
+}
+
+pub async fn probe(input_path: &str) -> Result<MediaInfo, Box<dyn std::error::Error>> {
+  let output = Command::new("ffprobe")
+    .args([
+      "-v", "error",
+      "-print_format", "json",
+      "-show_format",
+      "-show_streams",
+      input_path,
+    ])
+    .output()
+    .await?;
+
+  if !output.status.success() {
+    return Err(String::from_utf8_lossy(&output.stderr).trim().to_string().into());
+  }
+
+  Ok(serde_json::from_slice::<MediaInfo>(&output.stdout)?)
+}
@@ -0,0 +1,94 @@
+use serde::Serialize;
+use tauri::Window;
+use tokio::{
+  process::ChildStdout,
+  io::{BufReader, AsyncBufReadExt},
+};
+
+pub const PROGRESS_EVENT: &str = "hls-progress";
+
+#[derive(Serialize, Clone, Debug)]
+pub struct ProgressPayload {
+  job_id: String,
+  percent: f64,
+  fps: f64,
+  speed: f64,
+  eta: Option<f64>,
+  done: bool,
+}
+
+/// Reads ffmpeg's `-progress pipe:1` key=value blocks and forwards each block
+/// to the frontend as a `hls-progress` event.
+pub struct ProgressReporter {
+  window: Window,
+  job_id: String,
+  duration: f64,
+  // Media time already covered before this ffmpeg run, e.g. when resuming.
+  offset: f64,
+}
+
+impl ProgressReporter {
+  pub fn new(window: Window, job_id: String, duration: f64, offset: f64) -> Self {
+    Self {
+      window,
+      job_id,
+      duration,
+      offset,
+    }
+  }
+
+  pub async fn run(self, stdout: ChildStdout) {
+    let mut reader = BufReader::new(stdout).lines();
+    let mut out_time: f64 = 0.0;
+    let mut fps: f64 = 0.0;
+    let mut speed: f64 = 0.0;
+
+    while let Ok(Some(line)) = reader.next_line().await {
+      let (key, value) = match line.split_once('=') {
+        Some(pair) => pair,
+        None => continue,
+      };
+      match key {
+        "out_time_us" => {
+          if let Ok(us) = value.trim().parse::<i64>() {
+            out_time = us.max(0) as f64 / 1e6;
+          }
+        },
+        "fps" => fps = value.trim().parse::<f64>().unwrap_or(0.0),
+        "speed" => speed = value.trim().trim_end_matches('x').parse::<f64>().unwrap_or(0.0),
+        "progress" => {
+          let done = value.trim() == "end";
+          self.emit(self.offset + out_time, fps, speed, done);
+          if done {
+            break;
+          }
+        },
+        _ => {},
+      }
+    }
+  }
+
+  fn emit(&self, position: f64, fps: f64, speed: f64, done: bool) {
+    let percent = if done {
+      100.0
+    } else if self.duration > 0.0 {
+      (position / self.duration * 100.0).min(100.0)
+    } else {
+      0.0
+    };
+    let eta = if speed > 0.0 && self.duration > 0.0 {
+      Some(((self.duration - position) / speed).max(0.0))
+    } else {
+      None
+    };
+
+    let _ = self.window.emit(PROGRESS_EVENT, ProgressPayload {
+      job_id: self.job_id.clone(),
+      percent,
+      fps,
+      speed,
+      eta,
+      done,
+    });
+  }
+}
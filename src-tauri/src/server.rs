@@ -1,24 +1,145 @@
-use std::fs;
-use actix_web::{HttpResponse, HttpRequest};
+use std::path::{Component, Path, PathBuf};
+use rand::{distributions::Alphanumeric, Rng};
+use lazy_static::lazy_static;
+use actix_files::NamedFile;
+use actix_web::{
+  web,
+  HttpRequest,
+  HttpResponse,
+  http::header::{self, HeaderValue},
+};
+use crate::{
+  cache::{record_access, cache_state_by_dir, direct_source_by_dir, CacheState},
+  paths::cache_root,
+  hls_command::wait_for_segment,
+};
 
 pub const SERVER_ADDRESS: &str = "localhost:3117";
 
-pub async fn serve_hls(req: HttpRequest) -> HttpResponse {
-  let path = req.path().trim_start_matches('/');
-  
-  if let Ok(content) = fs::read_to_string(path) {
-    HttpResponse::Ok()
-      .content_type("application/vnd.apple.mpegurl")
-      .body(content)
-  } else if let Ok(content) = fs::read(path) {
-    HttpResponse::Ok()
-      .content_type("video/MP2T")
-      .body(content)
+/// Origins the webview loads the app from. The dev server only in debug builds.
+pub const ALLOWED_ORIGINS: &[&str] = &[
+  "tauri://localhost",
+  "https://tauri.localhost",
+  #[cfg(debug_assertions)]
+  "http://localhost:5173",
+];
+
+lazy_static! {
+  // Regenerated every launch, so URLs can't be guessed by other local pages.
+  static ref ACCESS_TOKEN: String = rand::thread_rng()
+    .sample_iter(&Alphanumeric)
+    .take(32)
+    .map(char::from)
+    .collect();
+}
+
+// Maps a request path onto a file inside the cache root. Anything that could
+// step outside of it, including symlinks pointing elsewhere, is refused.
+fn resolve_path(path: &str) -> Option<PathBuf> {
+  let relative_path = Path::new(path);
+  if !relative_path.components().all(|component| matches!(component, Component::Normal(_))) {
+    return None;
+  }
+
+  let root = cache_root().canonicalize().ok()?;
+  let resolved = root.join(relative_path).canonicalize().ok()?;
+  if resolved.starts_with(&root) && resolved.is_file() {
+    Some(resolved)
   } else {
-    HttpResponse::NotFound().body("File not found")
+    None
+  }
+}
+
+fn content_type(file_name: &str) -> Option<&'static str> {
+  let extension = Path::new(file_name).extension()?.to_str()?.to_ascii_lowercase();
+  let content_type = match extension.as_str() {
+    "m3u8" => "application/vnd.apple.mpegurl",
+    "ts" => "video/mp2t",
+    "m4s" => "video/iso.segment",
+    "mp4" => "video/mp4",
+    "webm" => "video/webm",
+    "aac" => "audio/aac",
+    "vtt" => "text/vtt; charset=utf-8",
+    "srt" => "application/x-subrip; charset=utf-8",
+    "ass" | "ssa" => "text/x-ssa; charset=utf-8",
+    "jpg" | "jpeg" => "image/jpeg",
+    "png" => "image/png",
+    "json" => "application/json",
+    _ => return None,
+  };
+
+  Some(content_type)
+}
+
+// Segments never change once written, since a regenerated entry gets a new
+// directory. Playlists grow until the entry is complete, and subtitles may be
+// rewritten with another encoding, so everything else is revalidated.
+fn cache_control(output_dir_name: &str, file_name: &str) -> &'static str {
+  let extension = Path::new(file_name).extension().map(|extension| extension.to_string_lossy().to_ascii_lowercase());
+  match extension.as_deref() {
+    Some("ts" | "m4s") => "public, max-age=31536000, immutable",
+    Some("m3u8") if cache_state_by_dir(output_dir_name) == Some(CacheState::Complete) => "public, max-age=3600",
+    _ => "no-cache",
   }
 }
 
-pub fn get_file_url(path: &str) -> String {
-  "http://".to_string() + SERVER_ADDRESS + "/" + &path
-}
\ No newline at end of file
+// Direct play entries expose the original file under this name.
+fn is_source_file(file_name: &str) -> bool {
+  matches!(file_name, "source.mp4" | "source.webm")
+}
+
+pub async fn serve_hls(req: HttpRequest, path: web::Path<(String, String)>) -> HttpResponse {
+  let (token, path) = path.into_inner();
+  if token != *ACCESS_TOKEN {
+    return HttpResponse::Forbidden().body("Invalid access token");
+  }
+
+  let (output_dir_name, file_name) = match path.split_once('/') {
+    Some(parts) => parts,
+    None => return HttpResponse::NotFound().body("File not found"),
+  };
+  let content_type = match content_type(file_name) {
+    Some(content_type) => content_type,
+    None => return HttpResponse::Forbidden().body("Unsupported file type"),
+  };
+  let resolved = if is_source_file(file_name) {
+    direct_source_by_dir(output_dir_name).map(PathBuf::from)
+  } else {
+    match resolve_path(&path) {
+      // A segment past what an on-demand transcode has written so far.
+      None if wait_for_segment(output_dir_name, file_name).await => resolve_path(&path),
+      resolved => resolved,
+    }
+  };
+  let file = match resolved {
+    Some(resolved) => NamedFile::open_async(resolved).await,
+    None => return HttpResponse::NotFound().body("File not found"),
+  };
+  let file = match file {
+    Ok(file) => file
+      .set_content_type(content_type.parse().unwrap())
+      .use_etag(true)
+      .use_last_modified(true)
+      .disable_content_disposition(),
+    Err(_) => return HttpResponse::NotFound().body("File not found"),
+  };
+  // A direct play source is fetched in many ranges, only its first one counts as a play.
+  let is_first_request = req.headers()
+    .get(header::RANGE)
+    .and_then(|range| range.to_str().ok())
+    .map_or(true, |range| range.starts_with("bytes=0-"));
+  record_access(output_dir_name, file_name.ends_with(".m3u8") || (is_source_file(file_name) && is_first_request));
+
+  // NamedFile takes care of HEAD, Range/206 and the conditional request headers.
+  let mut response = file.into_response(&req);
+  response.headers_mut().insert(
+    header::CACHE_CONTROL,
+    HeaderValue::from_static(cache_control(output_dir_name, file_name)),
+  );
+
+  response
+}
+
+pub fn get_file_url(output_dir_name: &str, file_name: &str) -> String {
+  "http://".to_string() + SERVER_ADDRESS + "/" + &ACCESS_TOKEN + "/" + output_dir_name + "/" + file_name
+}
@@ -0,0 +1,200 @@
+use std::{
+  fs,
+  collections::BTreeMap,
+  path::PathBuf,
+  sync::RwLock,
+};
+use regex::Regex;
+use lazy_static::lazy_static;
+use serde::{Serialize, Deserialize};
+use crate::{
+  asr::AsrProviderKind,
+  asr_local::LocalAsrSettings,
+  cache::evict_cache,
+  playlist::{SegmentFormat, SegmentOptions},
+};
+
+pub const APP_DIR_NAME: &str = "my-player";
+const SETTINGS_FILE_NAME: &str = "settings.json";
+const DEFAULT_CACHE_MAX_BYTES: u64 = 10 * 1024 * 1024 * 1024;
+const MAX_SEGMENT_DURATION: u32 = 60;
+
+/// One rung of an adaptive bitrate ladder.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct RenditionProfile {
+  pub name: String,
+  pub height: u32,
+  /// Video bitrate in kbit/s.
+  pub video_bitrate: u32,
+  /// Audio bitrate in kbit/s.
+  pub audio_bitrate: u32,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct Settings {
+  /// Where HLS output is cached. `None` uses the platform cache directory.
+  pub cache_dir: Option<String>,
+  /// Upper bound for the whole HLS cache. `None` disables the size limit.
+  pub cache_max_bytes: Option<u64>,
+  /// Entries not played for this many days are evicted. `None` keeps them.
+  pub cache_max_age_days: Option<u64>,
+  /// Play MP4/WebM files the webview supports directly instead of via HLS.
+  pub direct_play: bool,
+  /// Ladder from `abr_profiles` to transcode into. `None` keeps a single
+  /// rendition at the source resolution.
+  pub abr_profile: Option<String>,
+  pub abr_profiles: BTreeMap<String, Vec<RenditionProfile>>,
+  /// fMP4 also lets HEVC and AV1 sources be copied instead of re-encoded.
+  pub segment_format: SegmentFormat,
+  /// Target segment length in seconds.
+  pub segment_duration: u32,
+  /// Segment file name pattern without the extension. Must contain a single
+  /// `%d` or `%0Nd`; five digits cover about eleven days of 10 s segments.
+  pub segment_name: String,
+  /// List every segment up front and restart re-encodes where the player
+  /// seeks to, instead of transcoding strictly from start to end.
+  pub on_demand_seek: bool,
+  /// Language tags of the audio tracks to keep, e.g. `["jpn", "eng"]`. Empty
+  /// keeps every track.
+  pub audio_languages: Vec<String>,
+  /// Speech recognition engine used to generate subtitles.
+  pub asr_provider: AsrProviderKind,
+  pub local_asr: LocalAsrSettings,
+}
+
+fn rendition(name: &str, height: u32, video_bitrate: u32, audio_bitrate: u32) -> RenditionProfile {
+  RenditionProfile { name: name.to_string(), height, video_bitrate, audio_bitrate }
+}
+
+fn default_abr_profiles() -> BTreeMap<String, Vec<RenditionProfile>> {
+  BTreeMap::from([
+    ("standard".to_string(), vec![
+      rendition("1080p", 1080, 5000, 192),
+      rendition("720p", 720, 2800, 128),
+      rendition("480p", 480, 1400, 128),
+    ]),
+    ("low".to_string(), vec![
+      rendition("720p", 720, 2000, 128),
+      rendition("480p", 480, 1000, 96),
+      rendition("360p", 360, 600, 96),
+    ]),
+  ])
+}
+
+impl Default for Settings {
+  fn default() -> Self {
+    Self {
+      cache_dir: None,
+      cache_max_bytes: Some(DEFAULT_CACHE_MAX_BYTES),
+      cache_max_age_days: None,
+      direct_play: true,
+      abr_profile: None,
+      abr_profiles: default_abr_profiles(),
+      segment_format: SegmentFormat::Ts,
+      segment_duration: 10,
+      segment_name: "seg_%05d".to_string(),
+      on_demand_seek: true,
+      audio_languages: Vec::new(),
+      asr_provider: AsrProviderKind::Bytedance,
+      local_asr: LocalAsrSettings::default(),
+    }
+  }
+}
+
+impl Settings {
+  fn validate(&self) -> Result<(), String> {
+    if self.segment_duration == 0 || self.segment_duration > MAX_SEGMENT_DURATION {
+      return Err(format!("segment_duration must be between 1 and {} seconds", MAX_SEGMENT_DURATION));
+    }
+    let segment_name_regex = Regex::new(r"^[A-Za-z0-9_-]*%(0[1-9])?d[A-Za-z0-9_-]*$").unwrap();
+    if !segment_name_regex.is_match(&self.segment_name) {
+      return Err("segment_name must contain a single %d or %0Nd and otherwise only letters, digits, '-' and '_'".to_string());
+    }
+    // Rung names go into ffmpeg's space separated `-var_stream_map` and name
+    // the rung's output directory, next to `source` and the `audio_N` ones.
+    let rung_name_regex = Regex::new(r"^[A-Za-z0-9_-]+$").unwrap();
+    let reserved_name_regex = Regex::new(r"^(source|audio_\d+)$").unwrap();
+    for (profile, ladder) in &self.abr_profiles {
+      for (i, rung) in ladder.iter().enumerate() {
+        if !rung_name_regex.is_match(&rung.name) || reserved_name_regex.is_match(&rung.name) {
+          return Err(format!(
+            "abr_profiles.{}: rung name \"{}\" must only contain letters, digits, '-' and '_' and not be \"source\" or \"audio_N\"",
+            profile, rung.name,
+          ));
+        }
+        if ladder[..i].iter().any(|other| other.name == rung.name) {
+          return Err(format!("abr_profiles.{}: rung name \"{}\" is used twice", profile, rung.name));
+        }
+        if rung.height == 0 {
+          return Err(format!("abr_profiles.{}: rung \"{}\" must have a height", profile, rung.name));
+        }
+      }
+    }
+    if self.local_asr.binary.trim().is_empty() {
+      return Err("local_asr.binary must name the recognizer to run".to_string());
+    }
+    if self.local_asr.threads == Some(0) {
+      return Err("local_asr.threads must be at least 1".to_string());
+    }
+
+    Ok(())
+  }
+
+  pub fn segment_options(&self) -> SegmentOptions {
+    SegmentOptions {
+      format: self.segment_format,
+      duration: self.segment_duration,
+      name: self.segment_name.clone(),
+    }
+  }
+}
+
+lazy_static! {
+  static ref SETTINGS: RwLock<Settings> = RwLock::new(Settings::default());
+}
+
+fn settings_path() -> Option<PathBuf> {
+  tauri::api::path::config_dir().map(|dir| dir.join(APP_DIR_NAME).join(SETTINGS_FILE_NAME))
+}
+
+/// Loads the settings file, falling back to the defaults when it is missing or invalid.
+pub fn init_settings() {
+  let loaded = settings_path()
+    .and_then(|path| fs::read_to_string(path).ok())
+    .and_then(|content| serde_json::from_str::<Settings>(&content).ok())
+    .filter(|settings| settings.validate().is_ok());
+
+  if let Some(loaded) = loaded {
+    *SETTINGS.write().unwrap() = loaded;
+  }
+}
+
+pub fn settings() -> Settings {
+  SETTINGS.read().unwrap().clone()
+}
+
+fn save_settings(settings: &Settings) -> Result<(), Box<dyn std::error::Error>> {
+  let path = settings_path().ok_or("No config directory on this platform")?;
+  if let Some(dir) = path.parent() {
+    fs::create_dir_all(dir)?;
+  }
+  fs::write(path, serde_json::to_vec_pretty(settings)?)?;
+
+  Ok(())
+}
+
+#[tauri::command]
+pub fn get_settings() -> Settings {
+  settings()
+}
+
+#[tauri::command]
+pub async fn set_settings(settings: Settings) -> Result<Settings, String> {
+  settings.validate()?;
+  save_settings(&settings).map_err(|err| err.to_string())?;
+  *SETTINGS.write().unwrap() = settings.clone();
+  evict_cache();
+
+  Ok(settings)
+}
@@ -1,49 +1,22 @@
-use url::Url;
-use reqwest::Client;
-use lazy_static::lazy_static;
 use serde::{Serialize, Deserialize};
-use serde_json::{Map, Value};
 use std::{
-  env,
   fs::{self, File},
-  io::{Read, Write},
+  io::Write,
 };
 use crate::{
-  cache::CACHE_MAP,
+  asr::{asr_provider, transcribe},
+  cache::{CACHE_MAP, SubtitleFile, SubtitleSource, cache_map_add_subtitle},
   server::get_file_url,
+  settings::settings,
+  paths::{entry_file, entry_file_str},
 };
 
-lazy_static! {
-  static ref VC_APP_ID: String = get_vc_app_var("VC_APP_ID");
-  static ref VC_APP_ACCESS_TOKEN: String = get_vc_app_var("VC_APP_ACCESS_TOKEN");
-  pub static ref HTTP_CLIENT: Client = Client::new();
-}
-
-#[derive(Deserialize)] 
-struct UploadEndpointResult {  
-  code: u64,
-  message: String,
-  id: String,
-}
-
-#[derive(Deserialize)]
-struct ResultQueryEndpointResult {  
-  code: u64,
-  message: String,
-  utterances: Vec<SubtitleEntry>,
-
-  #[serde(flatten)]
-  _unknown: Map<String, Value>,
-}
-
-#[derive(Deserialize)]
-struct SubtitleEntry {
-  text: String,
-  start_time: u64,
-  end_time: u64,
-
-  #[serde(flatten)]
-  _unknown: Map<String, Value>,
+/// A recognized utterance, timed in milliseconds.
+#[derive(Deserialize, Clone, Debug)]
+pub struct SubtitleEntry {
+  pub text: String,
+  pub start_time: u64,
+  pub end_time: u64,
 }
 
 #[derive(Serialize)]
@@ -53,47 +26,8 @@ pub struct ApiResponse {
   subtitle_url: String,
 }
 
-fn get_vc_app_var(key: &str) -> String {
-  env::var(key).unwrap_or_else(|_| "".to_string())
-}
-
-pub async fn upload_audio(file_path: &str) -> Result<String, Box<dyn std::error::Error>> {
-  let mut audio_file = File::open(file_path)?;
-  let mut buffer: Vec<u8> = Vec::new();
-
-  audio_file.read_to_end(&mut buffer)?;
-
-  let mut full_url = String::new();
-  {
-    let mut url = Url::parse("https://openspeech.bytedance.com/api/v1/vc/submit")?;
-    let mut builder = url.query_pairs_mut();
-    builder.append_pair("appid", &VC_APP_ID).append_pair("max_lines", "2");
-    full_url = builder.finish().to_string();
-  }
-
-  let response = HTTP_CLIENT
-    .post(&full_url)
-    .header("Content-Type", "audio/aac")
-    .header("Authorization", "Bearer; ".to_string() + &VC_APP_ACCESS_TOKEN)
-    .body(buffer)
-    .send()
-    .await?;
-
-  match response.status().is_success() {
-    true => {
-      let result = response.json::<UploadEndpointResult>().await?;
-      if result.code != 0 {
-        return Err(result.message.into());
-      } else {
-        return Ok(result.id);
-      }
-    },
-    false => Err("Failed to upload audio file".into()),
-  }
-}
-
 fn convert_to_vtt(entries: &Vec<SubtitleEntry>, output_dir_name: &str) -> Result<(), Box<dyn std::error::Error>> {  
-  let output_file_path = "hls/".to_string() + output_dir_name + "/subtitle.vtt";
+  let output_file_path = entry_file(output_dir_name, "subtitle.vtt");
   let mut output_file = File::create(&output_file_path)?; 
 
   writeln!(output_file, "WEBVTT\n")?; 
@@ -108,7 +42,7 @@ fn convert_to_vtt(entries: &Vec<SubtitleEntry>, output_dir_name: &str) -> Result
   Ok(())
 }
 
-fn format_time(millis: u64) -> String {  
+pub fn format_time(millis: u64) -> String {  
   let hours = millis / (1000 * 60 * 60);
   let minutes = (millis / (1000 * 60)) % 60;
   let seconds = (millis / 1000) % 60;
@@ -117,60 +51,50 @@ fn format_time(millis: u64) -> String {
   format!("{:02}:{:02}:{:02}.{:03}", hours, minutes, seconds, millis_part)
 }
 
-async fn get_order_result(order_id: &str) -> Result<Vec<SubtitleEntry>, Box<dyn std::error::Error>> {
-  let mut full_url = String::new();
-  {
-    let mut url = Url::parse("https://openspeech.bytedance.com/api/v1/vc/query")?;
-    let mut builder = url.query_pairs_mut();
-    builder.append_pair("appid", &VC_APP_ID).append_pair("id", order_id);
-    full_url = builder.finish().to_string();
-  }
-
-  let response = HTTP_CLIENT
-    .get(&full_url)
-    .header("Authorization", "Bearer; ".to_string() + &VC_APP_ACCESS_TOKEN)
-    .send()
-    .await?;
-
-  match response.status().is_success() {
-    true => {
-      let result = response.json::<ResultQueryEndpointResult>().await?;
-      if result.code != 0 {
-        return Err(result.message.into());
-      } else {
-        return Ok(result.utterances);
-      }
-    }
-    false => Err("Failed to get order result".into()),
-  }
-}
-
 #[tauri::command]
 pub async fn generate_subtitle(input_path: String) -> Result<ApiResponse, String> {
-  let mut output_dir_name = String::new();
-  {
-    let cache_map = CACHE_MAP.lock().unwrap();
-    let cache = cache_map.get(&input_path).unwrap();
-    output_dir_name = cache.output_dir_name.clone();
-    drop(cache_map);
-  }
-  let audio_path = "hls/".to_string() + &output_dir_name + "/audio.aac";
-  let subtitle_path = "hls/".to_string() + &output_dir_name + "/subtitle.vtt";
+  let output_dir_name = match CACHE_MAP.lock().unwrap().get(&input_path) {
+    Some(cache) => cache.output_dir_name.clone(),
+    None => return Ok(ApiResponse {
+      success: false,
+      message: "No cache entry for this file".to_string(),
+      subtitle_url: String::new(),
+    }),
+  };
+  let audio_path = entry_file_str(&output_dir_name, "audio.aac");
+  let subtitle_path = entry_file(&output_dir_name, "subtitle.vtt");
   if fs::metadata(&subtitle_path).is_ok() {
     return Ok(ApiResponse {
       success: true,
       message: "Subtitle generated successfully.".to_string(),
-      subtitle_url: get_file_url(&subtitle_path),
+      subtitle_url: get_file_url(&output_dir_name, "subtitle.vtt"),
     });
   }
 
-  let order_id = upload_audio(&audio_path).await.unwrap();
-  let subtitle = get_order_result(&order_id).await.unwrap();
-  convert_to_vtt(&subtitle, &output_dir_name).unwrap();
+  let provider = asr_provider(&settings());
+  let subtitle = match transcribe(provider.as_ref(), &audio_path).await.map_err(|err| err.to_string()) {
+    Ok(subtitle) => subtitle,
+    Err(message) => return Ok(ApiResponse {
+      success: false,
+      message,
+      subtitle_url: String::new(),
+    }),
+  };
+  convert_to_vtt(&subtitle, &output_dir_name).map_err(|err| err.to_string())?;
+  cache_map_add_subtitle(&input_path, SubtitleFile {
+    file: "subtitle.vtt".to_string(),
+    language: None,
+    title: None,
+    source: SubtitleSource::Asr,
+    stream_index: None,
+    source_file: None,
+    encoding: None,
+    encoding_override: None,
+  });
 
   Ok(ApiResponse {
     success: true,
     message: "Subtitle generated successfully.".to_string(),
-    subtitle_url: get_file_url(&subtitle_path),
+    subtitle_url: get_file_url(&output_dir_name, "subtitle.vtt"),
   })
 }
\ No newline at end of file
@@ -0,0 +1,513 @@
+use regex::{Captures, Regex};
+use lazy_static::lazy_static;
+use chardetng::EncodingDetector;
+use encoding_rs::{Encoding, UTF_8};
+use crate::subtitle::format_time;
+
+// MicroDVD files without a frame rate header are most often made for 23.976 fps.
+pub const DEFAULT_FRAME_RATE: f64 = 24000.0 / 1001.0;
+// Used when a MicroDVD cue has no end frame.
+const DEFAULT_CUE_MILLIS: u64 = 3000;
+
+lazy_static! {
+  static ref SRT_TIMING: Regex = Regex::new(
+    r"(\d+):(\d{2}):(\d{2})[,.](\d{1,3})\s*-->\s*(\d+):(\d{2}):(\d{2})[,.](\d{1,3})"
+  ).unwrap();
+  // WebVTT may leave out the hours.
+  static ref VTT_TIMING: Regex = Regex::new(
+    r"(?:(\d+):)?(\d{2}):(\d{2})\.(\d{3})\s*-->\s*(?:(\d+):)?(\d{2}):(\d{2})\.(\d{3})"
+  ).unwrap();
+  static ref SRT_UNSUPPORTED_TAG: Regex = Regex::new(r"(?i)</?(font|span)[^>]*>|\{\\[^}]*\}").unwrap();
+  static ref ASS_OVERRIDE: Regex = Regex::new(r"\{[^}]*\}").unwrap();
+  static ref ASS_DRAWING: Regex = Regex::new(r"\\p[1-9]").unwrap();
+  static ref MICRODVD_LINE: Regex = Regex::new(r"^\{(\d+)\}\{(\d*)\}(.*)$").unwrap();
+  static ref SUBVIEWER_TIMING: Regex = Regex::new(
+    r"^(\d+):(\d{2}):(\d{2})\.(\d{1,3}),(\d+):(\d{2}):(\d{2})\.(\d{1,3})$"
+  ).unwrap();
+  static ref TTML_PARAGRAPH: Regex = Regex::new(r"(?s)<(?:\w+:)?p\b([^>]*)>(.*?)</(?:\w+:)?p>").unwrap();
+  static ref TTML_BREAK: Regex = Regex::new(r"<(?:\w+:)?br\s*/?>").unwrap();
+  static ref TTML_CLOCK: Regex = Regex::new(r"^(\d+):(\d{2}):(\d{2})(?:\.(\d+)|:(\d+))?$").unwrap();
+  static ref TTML_OFFSET: Regex = Regex::new(r"^(\d+(?:\.\d+)?)(h|ms|m|s|f|t)$").unwrap();
+  static ref XML_ATTRIBUTE: Regex = Regex::new(r#"(?:^|\s)(?:\w+:)?(\w+)\s*=\s*"([^"]*)""#).unwrap();
+  static ref XML_NUMERIC_ENTITY: Regex = Regex::new(r"&#(x?)([0-9a-fA-F]+);").unwrap();
+  static ref XML_TAG: Regex = Regex::new(r"<[^>]+>").unwrap();
+  static ref WHITESPACE: Regex = Regex::new(r"\s+").unwrap();
+}
+
+/// Text subtitle formats that can be converted to WebVTT.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum SubtitleFormat {
+  Srt,
+  Vtt,
+  Ass,
+  MicroDvd,
+  SubViewer,
+  Ttml,
+}
+
+/// A timed line of text, in milliseconds.
+pub struct Cue {
+  pub start: u64,
+  pub end: u64,
+  pub text: String,
+}
+
+impl SubtitleFormat {
+  /// Picks the format from the extension. `.sub` is shared by MicroDVD,
+  /// SubViewer and the bitmap VobSub format, so its content decides.
+  pub fn detect(extension: &str, content: &str) -> Option<Self> {
+    match extension.to_ascii_lowercase().as_str() {
+      "srt" => Some(SubtitleFormat::Srt),
+      "vtt" => Some(SubtitleFormat::Vtt),
+      "ass" | "ssa" => Some(SubtitleFormat::Ass),
+      "ttml" | "dfxp" | "xml" => Some(SubtitleFormat::Ttml),
+      "sub" => {
+        let mut lines = content.lines().map(str::trim).filter(|line| !line.is_empty());
+        if lines.clone().take(10).any(|line| MICRODVD_LINE.is_match(line)) {
+          Some(SubtitleFormat::MicroDvd)
+        } else if lines.any(|line| line.eq_ignore_ascii_case("[INFORMATION]") || SUBVIEWER_TIMING.is_match(line)) {
+          Some(SubtitleFormat::SubViewer)
+        } else {
+          None
+        }
+      },
+      _ => None,
+    }
+  }
+}
+
+fn detect_encoding(bytes: &[u8]) -> &'static Encoding {
+  if let Some((encoding, _)) = Encoding::for_bom(bytes) {
+    return encoding;
+  }
+  if std::str::from_utf8(bytes).is_ok() {
+    return UTF_8;
+  }
+  let mut detector = EncodingDetector::new();
+  detector.feed(bytes, true);
+
+  detector.guess(None, true)
+}
+
+/// Decodes a subtitle file to text. A byte order mark wins, then valid UTF-8,
+/// then a guess from the content, which tells GBK, Big5, Shift_JIS and the
+/// like apart. `label` (e.g. "gb18030", "big5") forces an encoding instead.
+pub fn decode(bytes: &[u8], label: Option<&str>) -> Result<(String, &'static Encoding), String> {
+  let encoding = match label {
+    Some(label) => Encoding::for_label(label.trim().as_bytes()).ok_or_else(|| format!("Unknown encoding: {}", label))?,
+    None => detect_encoding(bytes),
+  };
+  let (text, _) = encoding.decode_with_bom_removal(bytes);
+
+  Ok((text.into_owned(), encoding))
+}
+
+/// Converts decoded subtitle text to WebVTT. `frame_rate` is only used for
+/// MicroDVD files, which count frames, when they don't state their own.
+pub fn to_vtt(format: SubtitleFormat, content: &str, frame_rate: f64) -> String {
+  let cues = match format {
+    SubtitleFormat::Vtt if content.trim_start().starts_with("WEBVTT") => return content.to_string(),
+    SubtitleFormat::Srt => parse_srt(content),
+    SubtitleFormat::Vtt => parse_vtt(content),
+    SubtitleFormat::Ass => parse_ass(content),
+    SubtitleFormat::MicroDvd => parse_microdvd(content, frame_rate),
+    SubtitleFormat::SubViewer => parse_subviewer(content),
+    SubtitleFormat::Ttml => parse_ttml(content),
+  };
+
+  let mut vtt = String::from("WEBVTT\n\n");
+  for cue in cues.iter().filter(|cue| !cue.text.trim().is_empty() && cue.end > cue.start) {
+    vtt += &format!("{} --> {}\n{}\n\n", format_time(cue.start), format_time(cue.end), cue.text.trim());
+  }
+
+  vtt
+}
+
+// Hours, minutes, seconds and a fraction of a second written with 1-3 digits.
+fn clock_millis(captures: &Captures, first_group: usize) -> u64 {
+  let number = |group: usize| captures.get(group).map_or(0, |m| m.as_str().parse::<u64>().unwrap_or(0));
+  let fraction = captures.get(first_group + 3).map_or("0", |m| m.as_str());
+  let millis = format!("{:0<3}", fraction).parse::<u64>().unwrap_or(0);
+
+  ((number(first_group) * 60 + number(first_group + 1)) * 60 + number(first_group + 2)) * 1000 + millis
+}
+
+// Escapes text that came out of a format without markup of its own.
+fn escape_text(text: &str) -> String {
+  text.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;")
+}
+
+pub fn parse_srt(content: &str) -> Vec<Cue> {
+  parse_timed_blocks(content, &SRT_TIMING)
+}
+
+/// Reads WebVTT cues. Header, NOTE and STYLE blocks are skipped since they
+/// carry no timing line.
+pub fn parse_vtt(content: &str) -> Vec<Cue> {
+  parse_timed_blocks(content, &VTT_TIMING)
+}
+
+// Blocks of a timing line followed by text lines, ended by a blank line.
+fn parse_timed_blocks(content: &str, timing: &Regex) -> Vec<Cue> {
+  let mut cues = Vec::new();
+  let mut current: Option<Cue> = None;
+
+  for line in content.lines() {
+    if let Some(captures) = timing.captures(line) {
+      cues.extend(current.take());
+      current = Some(Cue { start: clock_millis(&captures, 1), end: clock_millis(&captures, 5), text: String::new() });
+      continue;
+    }
+    match current.as_mut() {
+      Some(cue) if !line.trim().is_empty() => {
+        if !cue.text.is_empty() {
+          cue.text.push('\n');
+        }
+        cue.text += &SRT_UNSUPPORTED_TAG.replace_all(line.trim_end(), "");
+      },
+      Some(_) => cues.extend(current.take()),
+      None => (),
+    }
+  }
+  cues.extend(current);
+
+  cues
+}
+
+// "H:MM:SS.cc", with centiseconds.
+fn parse_ass_time(value: &str) -> Option<u64> {
+  let mut parts = value.trim().splitn(3, ':');
+  let hours = parts.next()?.parse::<u64>().ok()?;
+  let minutes = parts.next()?.parse::<u64>().ok()?;
+  let seconds = parts.next()?.parse::<f64>().ok()?;
+
+  Some((hours * 3600 + minutes * 60) * 1000 + (seconds * 1000.0).round() as u64)
+}
+
+fn parse_ass(content: &str) -> Vec<Cue> {
+  let mut cues = Vec::new();
+  let mut in_events = false;
+  let mut fields: Vec<String> = ["layer", "start", "end", "style", "name", "marginl", "marginr", "marginv", "effect", "text"]
+    .iter()
+    .map(|field| field.to_string())
+    .collect();
+
+  for line in content.lines() {
+    let line = line.trim();
+    if line.starts_with('[') {
+      in_events = line.eq_ignore_ascii_case("[events]");
+      continue;
+    }
+    if !in_events {
+      continue;
+    }
+    if let Some(format) = line.strip_prefix("Format:") {
+      fields = format.split(',').map(|field| field.trim().to_ascii_lowercase()).collect();
+      continue;
+    }
+
+    let dialogue = match line.strip_prefix("Dialogue:") {
+      Some(dialogue) => dialogue,
+      None => continue,
+    };
+    // The text is last and may itself contain commas.
+    let values: Vec<&str> = dialogue.splitn(fields.len(), ',').collect();
+    let value = |name: &str| fields.iter().position(|field| field == name).and_then(|index| values.get(index)).copied();
+    let text = value("text").unwrap_or("");
+    if ASS_DRAWING.is_match(text) {
+      continue;
+    }
+    let (start, end) = match (value("start").and_then(parse_ass_time), value("end").and_then(parse_ass_time)) {
+      (Some(start), Some(end)) => (start, end),
+      _ => continue,
+    };
+    let text = ASS_OVERRIDE.replace_all(text, "")
+      .replace("\\N", "\n")
+      .replace("\\n", "\n")
+      .replace("\\h", " ");
+
+    cues.push(Cue { start, end, text: escape_text(&text) });
+  }
+  cues.sort_by_key(|cue| cue.start);
+
+  cues
+}
+
+fn parse_microdvd(content: &str, frame_rate: f64) -> Vec<Cue> {
+  let lines: Vec<Captures> = content.lines().filter_map(|line| MICRODVD_LINE.captures(line.trim())).collect();
+
+  // Many files start with a "{1}{1}23.976" line stating their frame rate.
+  let mut frame_rate = frame_rate;
+  let mut skip = 0;
+  if let Some(first) = lines.first() {
+    if let Ok(stated) = first[3].trim().parse::<f64>() {
+      if stated > 0.0 {
+        frame_rate = stated;
+        skip = 1;
+      }
+    }
+  }
+  let frame_millis = |frame: u64| (frame as f64 / frame_rate * 1000.0).round() as u64;
+
+  let mut cues: Vec<Cue> = lines.iter()
+    .skip(skip)
+    .map(|captures| {
+      let start = frame_millis(captures[1].parse().unwrap_or(0));
+      let end = captures[2].parse().map(frame_millis).unwrap_or(0);
+      let text = ASS_OVERRIDE.replace_all(&captures[3], "").replace('|', "\n");
+      Cue { start, end, text: escape_text(&text) }
+    })
+    .collect();
+
+  // An empty end frame lasts until the next cue.
+  for index in 0..cues.len() {
+    if cues[index].end == 0 {
+      let next_start = cues.get(index + 1).map(|cue| cue.start);
+      let start = cues[index].start;
+      cues[index].end = next_start.unwrap_or(start + DEFAULT_CUE_MILLIS).min(start + DEFAULT_CUE_MILLIS);
+    }
+  }
+
+  cues
+}
+
+fn parse_subviewer(content: &str) -> Vec<Cue> {
+  let mut cues = Vec::new();
+  let mut current: Option<Cue> = None;
+
+  for line in content.lines().map(str::trim) {
+    if let Some(captures) = SUBVIEWER_TIMING.captures(line) {
+      cues.extend(current.take());
+      current = Some(Cue { start: clock_millis(&captures, 1), end: clock_millis(&captures, 5), text: String::new() });
+      continue;
+    }
+    match current.as_mut() {
+      Some(cue) if !line.is_empty() => {
+        if !cue.text.is_empty() {
+          cue.text.push('\n');
+        }
+        cue.text += &escape_text(&line.replace("[br]", "\n").replace("[BR]", "\n"));
+      },
+      Some(_) => cues.extend(current.take()),
+      None => (),
+    }
+  }
+  cues.extend(current);
+
+  cues
+}
+
+fn xml_attribute(attributes: &str, name: &str) -> Option<String> {
+  XML_ATTRIBUTE.captures_iter(attributes)
+    .find(|captures| &captures[1] == name)
+    .map(|captures| captures[2].to_string())
+}
+
+fn unescape_xml(text: &str) -> String {
+  let text = XML_NUMERIC_ENTITY.replace_all(text, |captures: &Captures| {
+    let radix = if captures[1].is_empty() { 10 } else { 16 };
+    u32::from_str_radix(&captures[2], radix).ok().and_then(char::from_u32).map(String::from).unwrap_or_default()
+  });
+
+  text.replace("&lt;", "<")
+    .replace("&gt;", ">")
+    .replace("&quot;", "\"")
+    .replace("&apos;", "'")
+    .replace("&amp;", "&")
+}
+
+// Clock times ("00:01:02.500", "00:01:02:12" with frames) or offsets with a
+// unit ("62.5s", "1500ms", "900t" in ticks).
+fn parse_ttml_time(value: &str, frame_rate: f64, tick_rate: f64) -> Option<u64> {
+  let value = value.trim();
+  if let Some(captures) = TTML_CLOCK.captures(value) {
+    let number = |group: usize| captures.get(group).map_or(0.0, |m| m.as_str().parse::<f64>().unwrap_or(0.0));
+    let fraction = captures.get(4).map_or(0.0, |m| format!("0.{}", m.as_str()).parse::<f64>().unwrap_or(0.0));
+    let frames = number(5) / frame_rate;
+    let seconds = number(1) * 3600.0 + number(2) * 60.0 + number(3) + fraction + frames;
+    return Some((seconds * 1000.0).round() as u64);
+  }
+
+  let captures = TTML_OFFSET.captures(value)?;
+  let amount = captures[1].parse::<f64>().ok()?;
+  let seconds = match &captures[2] {
+    "h" => amount * 3600.0,
+    "m" => amount * 60.0,
+    "s" => amount,
+    "ms" => amount / 1000.0,
+    "f" => amount / frame_rate,
+    _ => amount / tick_rate,
+  };
+
+  Some((seconds * 1000.0).round() as u64)
+}
+
+fn parse_ttml(content: &str) -> Vec<Cue> {
+  let root = content.find("<body").map_or(content, |body| &content[..body]);
+  let frame_rate = xml_attribute(root, "frameRate").and_then(|rate| rate.parse::<f64>().ok()).unwrap_or(30.0);
+  let tick_rate = xml_attribute(root, "tickRate").and_then(|rate| rate.parse::<f64>().ok()).unwrap_or(1.0);
+
+  let mut cues = Vec::new();
+  for captures in TTML_PARAGRAPH.captures_iter(content) {
+    let attributes = &captures[1];
+    let time = |name: &str| xml_attribute(attributes, name).and_then(|value| parse_ttml_time(&value, frame_rate, tick_rate));
+    let start = match time("begin") {
+      Some(start) => start,
+      None => continue,
+    };
+    let end = match (time("end"), time("dur")) {
+      (Some(end), _) => end,
+      (None, Some(duration)) => start + duration,
+      (None, None) => continue,
+    };
+
+    // Line breaks in the markup are insignificant, only <br/> breaks a line.
+    let text = WHITESPACE.replace_all(&captures[2], " ");
+    let text = TTML_BREAK.replace_all(&text, "\n");
+    let text = unescape_xml(&XML_TAG.replace_all(&text, ""));
+    let text = text.lines().map(str::trim).collect::<Vec<_>>().join("\n");
+
+    cues.push(Cue { start, end, text: escape_text(&text) });
+  }
+  cues.sort_by_key(|cue| cue.start);
+
+  cues
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn cue_lines(vtt: &str) -> Vec<&str> {
+    vtt.lines().skip(2).filter(|line| !line.is_empty()).collect()
+  }
+
+  #[test]
+  fn srt_cues_keep_inline_styling() {
+    let srt = "1\r\n00:00:01,5 --> 00:00:02,000\r\n<font color=\"red\">Hi</font> <i>there</i>\r\n\r\n2\r\n00:00:03,000 --> 00:00:04,000\r\nBye\r\n";
+
+    assert_eq!(
+      cue_lines(&to_vtt(SubtitleFormat::Srt, srt, DEFAULT_FRAME_RATE)),
+      ["00:00:01.500 --> 00:00:02.000", "Hi <i>there</i>", "00:00:03.000 --> 00:00:04.000", "Bye"],
+    );
+  }
+
+  #[test]
+  fn ass_follows_format_line_and_keeps_commas_in_text() {
+    let ass = "[Script Info]\nTitle: Test\n\n[Events]\n\
+      Format: Layer, Style, Start, End, Name, MarginL, MarginR, MarginV, Effect, Text\n\
+      Dialogue: 0,Default,0:00:05.50,0:00:07.00,,0,0,0,,{\\an8}Well, hello,\\Nworld <3\n\
+      Comment: 0,Default,0:00:01.00,0:00:02.00,,0,0,0,,Not shown\n\
+      Dialogue: 0,Default,0:00:01.00,0:00:02.00,,0,0,0,,First\n";
+
+    assert_eq!(
+      cue_lines(&to_vtt(SubtitleFormat::Ass, ass, DEFAULT_FRAME_RATE)),
+      ["00:00:01.000 --> 00:00:02.000", "First", "00:00:05.500 --> 00:00:07.000", "Well, hello,", "world &lt;3"],
+    );
+  }
+
+  #[test]
+  fn microdvd_uses_frame_rate_header() {
+    let sub = "{1}{1}25.000\n{25}{50}{y:i}Hello|World\n{75}{}Open ended\n";
+
+    assert_eq!(SubtitleFormat::detect("sub", sub), Some(SubtitleFormat::MicroDvd));
+    assert_eq!(
+      cue_lines(&to_vtt(SubtitleFormat::MicroDvd, sub, DEFAULT_FRAME_RATE)),
+      ["00:00:01.000 --> 00:00:02.000", "Hello", "World", "00:00:03.000 --> 00:00:06.000", "Open ended"],
+    );
+  }
+
+  #[test]
+  fn microdvd_without_header_uses_given_frame_rate() {
+    let sub = "{50}{100}Hello\n";
+
+    assert_eq!(cue_lines(&to_vtt(SubtitleFormat::MicroDvd, sub, 50.0)), ["00:00:01.000 --> 00:00:02.000", "Hello"]);
+  }
+
+  #[test]
+  fn subviewer_breaks_lines() {
+    let sub = "[INFORMATION]\n[TITLE]Test\n[END INFORMATION]\n\n00:00:01.00,00:00:02.50\nHi[br]there\n";
+
+    assert_eq!(SubtitleFormat::detect("sub", sub), Some(SubtitleFormat::SubViewer));
+    assert_eq!(
+      cue_lines(&to_vtt(SubtitleFormat::SubViewer, sub, DEFAULT_FRAME_RATE)),
+      ["00:00:01.000 --> 00:00:02.500", "Hi", "there"],
+    );
+  }
+
+  #[test]
+  fn ttml_handles_ticks_durations_and_frames() {
+    let ttml = r#"<?xml version="1.0"?>
+      <tt xmlns="http://www.w3.org/ns/ttml" xmlns:ttp="http://www.w3.org/ns/ttml#parameter" ttp:tickRate="10000000" ttp:frameRate="25">
+        <body><div>
+          <p begin="10000000t" end="25000000t">Fish &amp; chips<br/>
+            for   two</p>
+          <tt:p begin="3.5s" dur="500ms"><span>Styled</span> &#x263A;</tt:p>
+          <p begin="00:00:05:10" dur="00:00:01.000">Frames</p>
+        </div></body>
+      </tt>"#;
+
+    assert_eq!(
+      cue_lines(&to_vtt(SubtitleFormat::Ttml, ttml, DEFAULT_FRAME_RATE)),
+      [
+        "00:00:01.000 --> 00:00:02.500", "Fish &amp; chips", "for two",
+        "00:00:03.500 --> 00:00:04.000", "Styled \u{263A}",
+        "00:00:05.400 --> 00:00:06.400", "Frames",
+      ],
+    );
+  }
+
+  #[test]
+  fn binary_sub_is_not_detected() {
+    assert_eq!(SubtitleFormat::detect("sub", "\u{0}\u{1}\u{ba}garbage"), None);
+  }
+
+  const SIMPLIFIED: &str = "1\r\n00:00:01,000 --> 00:00:03,000\r\n这是一段简体中文字幕，用来测试编码检测是否正确。\r\n";
+  const TRADITIONAL: &str = "1\r\n00:00:01,000 --> 00:00:03,000\r\n這是一段繁體中文字幕，用來測試編碼偵測是否正確。\r\n";
+
+  #[test]
+  fn gb18030_subtitles_are_detected() {
+    let (bytes, _, _) = encoding_rs::GB18030.encode(SIMPLIFIED);
+    let (text, encoding) = decode(&bytes, None).unwrap();
+
+    assert_eq!(text, SIMPLIFIED);
+    assert!(encoding == encoding_rs::GBK || encoding == encoding_rs::GB18030, "detected {}", encoding.name());
+  }
+
+  #[test]
+  fn big5_subtitles_are_detected() {
+    let (bytes, _, _) = encoding_rs::BIG5.encode(TRADITIONAL);
+    let (text, encoding) = decode(&bytes, None).unwrap();
+
+    assert_eq!(text, TRADITIONAL);
+    assert_eq!(encoding, encoding_rs::BIG5);
+  }
+
+  #[test]
+  fn byte_order_mark_wins_and_is_removed() {
+    let mut utf16 = vec![0xFF, 0xFE];
+    utf16.extend("WEBVTT\n\n字幕".encode_utf16().flat_map(u16::to_le_bytes));
+    let (text, encoding) = decode(&utf16, None).unwrap();
+    assert_eq!(text, "WEBVTT\n\n字幕");
+    assert_eq!(encoding, encoding_rs::UTF_16LE);
+
+    let (text, encoding) = decode("\u{FEFF}WEBVTT".as_bytes(), None).unwrap();
+    assert_eq!(text, "WEBVTT");
+    assert_eq!(encoding, UTF_8);
+  }
+
+  #[test]
+  fn label_overrides_detection() {
+    let (bytes, _, _) = encoding_rs::BIG5.encode(TRADITIONAL);
+    let (text, encoding) = decode(&bytes, Some(" GB18030 ")).unwrap();
+
+    assert_eq!(encoding, encoding_rs::GB18030);
+    assert_eq!(text, encoding_rs::GB18030.decode(&bytes).0);
+    assert_ne!(text, TRADITIONAL);
+  }
+
+  #[test]
+  fn unknown_label_is_an_error() {
+    assert_eq!(decode(b"WEBVTT", Some("klingon")).unwrap_err(), "Unknown encoding: klingon");
+  }
+}
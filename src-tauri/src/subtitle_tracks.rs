@@ -0,0 +1,402 @@
+use std::{
+  fs,
+  ffi::OsStr,
+  path::{Path, PathBuf},
+};
+use serde::Serialize;
+use tokio::{process::Command, sync::watch};
+use crate::{
+  probe::{self, MediaInfo, StreamInfo},
+  cache::{CACHE_MAP, SubtitleFile, SubtitleSource, cache_map_add_subtitle, generate_dir_name},
+  subtitle_convert::{self, SubtitleFormat, DEFAULT_FRAME_RATE},
+  jobs::{attach_task, wait_or_kill},
+  paths::{entry_file, entry_file_str},
+  server::get_file_url,
+};
+
+// Text codecs ffmpeg can convert to WebVTT. Bitmap subtitles (PGS, DVD, DVB)
+// would need OCR and are skipped.
+const TEXT_SUBTITLE_CODECS: [&str; 7] = ["subrip", "srt", "ass", "ssa", "webvtt", "mov_text", "text"];
+const SIDECAR_EXTENSIONS: [&str; 8] = ["srt", "vtt", "ass", "ssa", "sub", "ttml", "dfxp", "xml"];
+// Folders next to the source that hold its subtitles, compared lowercased.
+const SIDECAR_DIRS: [&str; 4] = ["subs", "sub", "subtitles", "subtitle"];
+
+#[derive(Serialize)]
+pub struct SubtitleTrack {
+  file: String,
+  url: String,
+  language: Option<String>,
+  title: Option<String>,
+  source: SubtitleSource,
+  encoding: Option<String>,
+}
+
+#[derive(Serialize)]
+pub struct SubtitleListResponse {
+  success: bool,
+  message: String,
+  subtitles: Vec<SubtitleTrack>,
+}
+
+/// The text subtitle streams of the input, as the WebVTT files they are
+/// extracted to.
+pub fn embedded_subtitles(media_info: &MediaInfo) -> Vec<SubtitleFile> {
+  media_info.streams
+    .iter()
+    .filter(|stream| stream.codec_type == "subtitle" && TEXT_SUBTITLE_CODECS.contains(&stream.codec()))
+    .map(|stream| SubtitleFile {
+      file: format!("embedded_{}.vtt", stream.index),
+      language: stream.language().map(String::from),
+      title: stream.title().map(String::from),
+      source: SubtitleSource::Embedded,
+      stream_index: Some(stream.index),
+      source_file: None,
+      encoding: None,
+      encoding_override: None,
+    })
+    .collect()
+}
+
+/// Extracts the embedded subtitles not on disk yet in a single ffmpeg run,
+/// attached to the input's job. Each file is moved into place once complete,
+/// so a file that exists is always whole.
+pub fn extract_embedded_subtitles(
+  input_path: &str,
+  output_dir_name: &str,
+  subtitles: &[SubtitleFile],
+  mut cancel: watch::Receiver<bool>,
+) -> Result<(), std::io::Error> {
+  let pending: Vec<(usize, String)> = subtitles
+    .iter()
+    .filter(|subtitle| !entry_file(output_dir_name, &subtitle.file).is_file())
+    .filter_map(|subtitle| subtitle.stream_index.map(|index| (index, subtitle.file.clone())))
+    .collect();
+  if pending.is_empty() {
+    return Ok(());
+  }
+
+  let mut args = vec!["-y".to_string(), "-i".to_string(), input_path.to_string()];
+  for (index, file) in &pending {
+    args.extend([
+      "-map".to_string(), format!("0:{}", index),
+      "-c:s".to_string(), "webvtt".to_string(),
+      "-f".to_string(), "webvtt".to_string(),
+      entry_file_str(output_dir_name, &(file.clone() + ".tmp")),
+    ]);
+  }
+  let mut subtitle_extract_cmd = Command::new("ffmpeg").args(args).spawn()?;
+
+  let output_dir_name = output_dir_name.to_string();
+  attach_task(input_path, tokio::spawn(async move {
+    match wait_or_kill(&mut subtitle_extract_cmd, &mut cancel).await {
+      Some(status) if status.success() => {
+        for (_, file) in pending {
+          let _ = fs::rename(entry_file(&output_dir_name, &(file.clone() + ".tmp")), entry_file(&output_dir_name, &file));
+        }
+      },
+      Some(status) => println!("subtitle extraction failed: {}", status),
+      None => (),
+    }
+  }));
+
+  Ok(())
+}
+
+/// A subtitle file found next to the source.
+pub struct Sidecar {
+  pub path: PathBuf,
+  pub language: Option<String>,
+}
+
+fn sidecar_extension(path: &Path) -> Option<String> {
+  path.extension()
+    .and_then(OsStr::to_str)
+    .map(str::to_ascii_lowercase)
+    .filter(|extension| SIDECAR_EXTENSIONS.contains(&extension.as_str()))
+}
+
+fn sidecar_files(dir: &Path) -> Vec<PathBuf> {
+  let mut files: Vec<PathBuf> = fs::read_dir(dir)
+    .into_iter()
+    .flatten()
+    .flatten()
+    .map(|entry| entry.path())
+    .filter(|path| path.is_file() && sidecar_extension(path).is_some())
+    .collect();
+  files.sort();
+
+  files
+}
+
+// What follows the video's name in a subtitle's name, e.g. ".zh" in
+// "movie.zh.ass". `None` if the subtitle is named after another file.
+fn name_suffix<'a>(path: &'a Path, video_stem: &str) -> Option<&'a str> {
+  let stem = path.file_stem()?.to_str()?;
+  let prefix = stem.get(..video_stem.len())?;
+  let suffix = &stem[video_stem.len()..];
+  let separated = suffix.is_empty() || suffix.starts_with(|c: char| matches!(c, '.' | '_' | '-' | ' '));
+
+  if prefix.eq_ignore_ascii_case(video_stem) && separated { Some(suffix) } else { None }
+}
+
+// Maps the language names and codes seen in subtitle file names to the
+// ISO 639-2 codes ffprobe reports for embedded streams.
+fn language_code(token: &str) -> Option<&'static str> {
+  let code = match token.to_ascii_lowercase().as_str() {
+    "en" | "eng" | "english" => "eng",
+    "zh" | "chi" | "zho" | "chs" | "cht" | "chinese" => "chi",
+    "ja" | "jpn" | "japanese" => "jpn",
+    "ko" | "kor" | "korean" => "kor",
+    "fr" | "fre" | "fra" | "french" => "fre",
+    "de" | "ger" | "deu" | "german" => "ger",
+    "es" | "spa" | "spanish" => "spa",
+    "it" | "ita" | "italian" => "ita",
+    "pt" | "por" | "portuguese" => "por",
+    "ru" | "rus" | "russian" => "rus",
+    "ar" | "ara" | "arabic" => "ara",
+    "nl" | "dut" | "nld" | "dutch" => "dut",
+    "sv" | "swe" | "swedish" => "swe",
+    "no" | "nor" | "norwegian" => "nor",
+    "da" | "dan" | "danish" => "dan",
+    "fi" | "fin" | "finnish" => "fin",
+    "pl" | "pol" | "polish" => "pol",
+    "cs" | "cze" | "ces" | "czech" => "cze",
+    "hu" | "hun" | "hungarian" => "hun",
+    "ro" | "rum" | "ron" | "romanian" => "rum",
+    "uk" | "ukr" | "ukrainian" => "ukr",
+    "el" | "gre" | "ell" | "greek" => "gre",
+    "tr" | "tur" | "turkish" => "tur",
+    "he" | "heb" | "hebrew" => "heb",
+    "hi" | "hin" | "hindi" => "hin",
+    "th" | "tha" | "thai" => "tha",
+    "vi" | "vie" | "vietnamese" => "vie",
+    "id" | "ind" | "indonesian" => "ind",
+    _ => return None,
+  };
+
+  Some(code)
+}
+
+/// The language in a subtitle file name like "movie.zh-Hans.forced" or
+/// "2_English". The last recognised token wins.
+pub fn parse_language(name: &str) -> Option<String> {
+  name.split(|c: char| matches!(c, '.' | '_' | '-' | ' ' | '[' | ']' | '(' | ')'))
+    .rev()
+    .find_map(language_code)
+    .map(String::from)
+}
+
+/// Subtitle files for the input: ones named after it next to it
+/// ("movie.srt", "movie.zh.ass"), and ones in a `Subs` folder. That folder
+/// may hold files named after the video, a folder per episode, or, for a
+/// single film, files with any name.
+pub fn discover_sidecars(input_path: &str) -> Vec<Sidecar> {
+  let input_path = Path::new(input_path);
+  let (dir, video_stem) = match (input_path.parent(), input_path.file_stem().and_then(OsStr::to_str)) {
+    (Some(dir), Some(video_stem)) => (dir, video_stem),
+    _ => return Vec::new(),
+  };
+  let named = |files: Vec<PathBuf>| -> Vec<Sidecar> {
+    files.into_iter()
+      .filter_map(|path| {
+        let language = parse_language(name_suffix(&path, video_stem)?);
+        Some(Sidecar { path, language })
+      })
+      .collect()
+  };
+  let any_name = |files: Vec<PathBuf>| -> Vec<Sidecar> {
+    files.into_iter()
+      .map(|path| {
+        let language = path.file_stem().and_then(OsStr::to_str).and_then(parse_language);
+        Sidecar { path, language }
+      })
+      .collect()
+  };
+
+  let mut sidecars = named(sidecar_files(dir));
+  let sub_dirs = fs::read_dir(dir)
+    .into_iter()
+    .flatten()
+    .flatten()
+    .map(|entry| entry.path())
+    .filter(|path| path.is_dir())
+    .filter(|path| path.file_name()
+      .and_then(OsStr::to_str)
+      .map_or(false, |name| SIDECAR_DIRS.contains(&name.to_ascii_lowercase().as_str())));
+  for sub_dir in sub_dirs {
+    let episode_dir = sub_dir.join(video_stem);
+    if episode_dir.is_dir() {
+      sidecars.extend(any_name(sidecar_files(&episode_dir)));
+      continue;
+    }
+    let files = sidecar_files(&sub_dir);
+    let named_files = named(files.clone());
+    sidecars.extend(if named_files.is_empty() { any_name(files) } else { named_files });
+  }
+
+  sidecars
+}
+
+fn convert_sidecar(
+  sidecar: &Sidecar,
+  output_dir_name: &str,
+  frame_rate: f64,
+  encoding_override: Option<&str>,
+) -> Result<SubtitleFile, Box<dyn std::error::Error>> {
+  let extension = sidecar_extension(&sidecar.path).unwrap_or_default();
+  let (content, encoding) = subtitle_convert::decode(&fs::read(&sidecar.path)?, encoding_override)?;
+  // Binary VobSub .sub files match no text format.
+  let format = SubtitleFormat::detect(&extension, &content).ok_or("Unrecognized subtitle format")?;
+
+  let source_file = sidecar.path.to_string_lossy().to_string();
+  let file = format!("sidecar_{}.vtt", generate_dir_name(&source_file));
+  fs::write(entry_file(output_dir_name, &file), subtitle_convert::to_vtt(format, &content, frame_rate))?;
+
+  Ok(SubtitleFile {
+    file,
+    language: sidecar.language.clone(),
+    title: sidecar.path.file_name().map(|name| name.to_string_lossy().to_string()),
+    source: SubtitleSource::Sidecar,
+    stream_index: None,
+    source_file: Some(source_file),
+    encoding: Some(encoding.name().to_string()),
+    encoding_override: encoding_override.map(String::from),
+  })
+}
+
+// MicroDVD counts frames, so it is timed with the video's frame rate.
+async fn sidecar_frame_rate(input_path: &str, sidecars: &[Sidecar]) -> f64 {
+  if !sidecars.iter().any(|sidecar| sidecar_extension(&sidecar.path).as_deref() == Some("sub")) {
+    return DEFAULT_FRAME_RATE;
+  }
+
+  probe::probe(input_path).await
+    .ok()
+    .and_then(|info| info.video_stream().and_then(StreamInfo::frame_rate))
+    .unwrap_or(DEFAULT_FRAME_RATE)
+}
+
+/// Converts the sidecar subtitles of the input that its entry doesn't list
+/// yet, and adds them to it.
+pub async fn add_sidecar_subtitles(input_path: &str) {
+  let (output_dir_name, known) = match CACHE_MAP.lock().unwrap().get(input_path) {
+    Some(cache) => (
+      cache.output_dir_name.clone(),
+      cache.subtitles.iter().filter_map(|subtitle| subtitle.source_file.clone()).collect::<Vec<_>>(),
+    ),
+    None => return,
+  };
+  let sidecars: Vec<Sidecar> = discover_sidecars(input_path)
+    .into_iter()
+    .filter(|sidecar| !known.contains(&sidecar.path.to_string_lossy().to_string()))
+    .collect();
+  if sidecars.is_empty() {
+    return;
+  }
+  let frame_rate = sidecar_frame_rate(input_path, &sidecars).await;
+
+  for sidecar in sidecars {
+    match convert_sidecar(&sidecar, &output_dir_name, frame_rate, None) {
+      Ok(subtitle) => cache_map_add_subtitle(input_path, subtitle),
+      Err(err) => println!("failed to convert {}: {}", sidecar.path.display(), err),
+    }
+  }
+}
+
+fn subtitle_list(input_path: &str) -> SubtitleListResponse {
+  let cache_map = CACHE_MAP.lock().unwrap();
+  let cache = match cache_map.get(input_path) {
+    Some(cache) => cache,
+    None => return SubtitleListResponse {
+      success: false,
+      message: "No cache entry for this file".to_string(),
+      subtitles: Vec::new(),
+    },
+  };
+
+  let subtitles = cache.subtitles
+    .iter()
+    .filter(|subtitle| entry_file(&cache.output_dir_name, &subtitle.file).is_file())
+    .map(|subtitle| SubtitleTrack {
+      file: subtitle.file.clone(),
+      url: get_file_url(&cache.output_dir_name, &subtitle.file),
+      language: subtitle.language.clone(),
+      title: subtitle.title.clone(),
+      source: subtitle.source,
+      encoding: subtitle.encoding.clone(),
+    })
+    .collect();
+
+  SubtitleListResponse {
+    success: true,
+    message: "Subtitles listed".to_string(),
+    subtitles,
+  }
+}
+
+/// Every subtitle of the input that is ready to load: extracted embedded
+/// tracks, converted sidecar files and the generated one.
+#[tauri::command]
+pub async fn list_subtitles(input_path: String) -> Result<SubtitleListResponse, String> {
+  add_sidecar_subtitles(&input_path).await;
+
+  Ok(subtitle_list(&input_path))
+}
+
+/// Converts a sidecar subtitle again with the given encoding, for when
+/// detection guessed wrong. `None` goes back to detection.
+#[tauri::command]
+pub async fn set_subtitle_encoding(input_path: String, file: String, encoding: Option<String>) -> Result<SubtitleListResponse, String> {
+  let found = CACHE_MAP.lock().unwrap().get(&input_path).and_then(|cache| {
+    let subtitle = cache.subtitles.iter().find(|subtitle| subtitle.file == file)?;
+    let sidecar = Sidecar {
+      path: PathBuf::from(subtitle.source_file.as_ref()?),
+      language: subtitle.language.clone(),
+    };
+    Some((cache.output_dir_name.clone(), sidecar))
+  });
+  let (output_dir_name, sidecar) = match found {
+    Some(found) => found,
+    None => return Ok(SubtitleListResponse {
+      success: false,
+      message: "Only sidecar subtitles can be decoded again".to_string(),
+      subtitles: Vec::new(),
+    }),
+  };
+
+  let frame_rate = sidecar_frame_rate(&input_path, std::slice::from_ref(&sidecar)).await;
+  let converted = convert_sidecar(&sidecar, &output_dir_name, frame_rate, encoding.as_deref()).map_err(|err| err.to_string());
+  match converted {
+    Ok(subtitle) => cache_map_add_subtitle(&input_path, subtitle),
+    Err(err) => return Ok(SubtitleListResponse {
+      success: false,
+      message: err,
+      subtitles: Vec::new(),
+    }),
+  }
+
+  Ok(subtitle_list(&input_path))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn suffix_requires_video_name_and_separator() {
+    assert_eq!(name_suffix(Path::new("/films/Movie.zh-Hans.forced.srt"), "movie"), Some(".zh-Hans.forced"));
+    assert_eq!(name_suffix(Path::new("/films/movie.srt"), "movie"), Some(""));
+    assert_eq!(name_suffix(Path::new("/films/movie2.srt"), "movie"), None);
+    assert_eq!(name_suffix(Path::new("/films/other.srt"), "movie"), None);
+  }
+
+  #[test]
+  fn language_comes_from_name_tokens() {
+    assert_eq!(parse_language(".zh-Hans.forced").as_deref(), Some("chi"));
+    assert_eq!(parse_language(".en.sdh").as_deref(), Some("eng"));
+    assert_eq!(parse_language("2_English").as_deref(), Some("eng"));
+    assert_eq!(parse_language(".pt-BR").as_deref(), Some("por"));
+    assert_eq!(parse_language(".forced"), None);
+    assert_eq!(parse_language(""), None);
+  }
+}
@@ -2,7 +2,7 @@ import { $, component$, useVisibleTask$, Slot, useSignal, useStore, noSerialize
 import { isServer } from '@builder.io/qwik/build';
 import { listen } from '@tauri-apps/api/event';
 import { appWindow, LogicalSize } from '@tauri-apps/api/window';
-import { getHlsUrl, getSubtileUrl } from '~/utils';
+import { getHlsUrl, getSubtileUrl, listenHlsProgress, cancelHls } from '~/utils';
 import { LuX } from '@qwikest/icons/lucide';
 import TitleBar from '~/components/layouts/title-bar';
 import SpinnerIcon from '~/components/icons/spinner';
@@ -14,6 +14,7 @@ import type { NoSerialize, QRL } from '@builder.io/qwik';
 import type { EventCallback } from '@tauri-apps/api/event';
 import type Player from 'video.js/dist/types/player';
 import type HTMLTrackElement from 'video.js/dist/types/tracks/html-track-element';
+import type { IHlsProgress } from '~/utils';
 
 export default component$(() => {
   const videoPath = useSignal('');
@@ -21,6 +22,7 @@ export default component$(() => {
   const isShowControl = useSignal(false);
   const isLoadingSubtitle = useSignal(false);
   const duration = useSignal<number>();
+  const transcodeProgress = useSignal<IHlsProgress>();
   const store = useStore<{
     player: NoSerialize<Player>; 
     currentVideoPath: string;
@@ -35,6 +37,7 @@ export default component$(() => {
     if(!store.player || isServer) return;
 
     videoPath.value = store.currentVideoPath = payload;
+    transcodeProgress.value = void 0;
     isLoading.value = true;
     const { url: hlsUrl, duration: _duration } = await getHlsUrl(videoPath.value);
     duration.value = _duration;
@@ -43,6 +46,12 @@ export default component$(() => {
     store.player!.play();
   });
 
+  const handleHlsProgress: QRL<EventCallback<IHlsProgress>> = $(({ payload }) => {
+    if(payload.job_id !== store.currentVideoPath) return;
+
+    transcodeProgress.value = payload;
+  });
+
   const setSize = $(() => {
     if(!store.player || isServer) return;
     const _size = new LogicalSize(
@@ -94,16 +103,19 @@ export default component$(() => {
 
     store.player.currentTime(0);
     store.player.pause();
-    videoPath.value = '';
+    store.currentVideoPath && cancelHls(store.currentVideoPath);
+    videoPath.value = store.currentVideoPath = '';
     isLoadingSubtitle.value = false;
     isShowControl.value = false;
     duration.value = void 0;
+    transcodeProgress.value = void 0;
     isLoading.value = false;
   });
   
   useVisibleTask$(async ({ cleanup }) => {
     const unsubscribeOpenVideo = await listen('open-video', handleOpenVideo);
     const unsubscribeFileDrop = await listen('file-drop-event', handleOpenVideo);
+    const unsubscribeHlsProgress = await listenHlsProgress(handleHlsProgress);
     store.player = store.player || noSerialize(videojs('my-player', {
       preload: false,
       controls: true,
@@ -131,6 +143,7 @@ export default component$(() => {
         store.player?.dispose();
         unsubscribeOpenVideo();
         unsubscribeFileDrop();
+        unsubscribeHlsProgress();
       });
     }));
   }, { strategy: 'document-ready' });
@@ -144,9 +157,19 @@ export default component$(() => {
           <div id="player-container" class="w-full h-full fade-in" hidden={isLoading.value}>
             <video-js id="my-player" />
           </div>
-          <div id="loading-container" class="absolute w-full h-full bg-slate-800 flex justify-center items-center fade-in" hidden={!isLoading.value}>
+          <div id="loading-container" class="absolute w-full h-full bg-slate-800 flex flex-col justify-center items-center fade-in" hidden={!isLoading.value}>
             <SpinnerIcon class="text-[4em] text-slate-200" />
+            {transcodeProgress.value && (
+              <span class="mt-3 text-slate-400 text-sm select-none">
+                {transcodeProgress.value.percent.toFixed(1)}%
+              </span>
+            )}
           </div>
+          <div
+            class="absolute top-0 left-0 h-[2px] bg-slate-300 transition-[width] z-[100]"
+            style={{ width: `${transcodeProgress.value?.percent ?? 0}%` }}
+            hidden={!transcodeProgress.value || transcodeProgress.value.done}
+          />
         </div>
         <div
           class="fixed top-1/2 text-white text-[1.8em] p-[6px] rounded-lg right-[16px] -translate-y-1/2 backdrop-blur-md transition-colors cursor-pointer z-[100] fade-in"
@@ -0,0 +1,45 @@
+import { invoke } from '@tauri-apps/api/tauri';
+
+export interface ISubtitleFile {
+  file: string;
+  language: string | null;
+  title: string | null;
+};
+
+export interface ICacheEntry {
+  input_path: string;
+  duration: number;
+  disk_usage: number;
+  has_subtitles: boolean;
+  subtitles: ISubtitleFile[];
+  codecs: string[];
+  playback_mode: 'hls' | 'direct';
+  state: 'in_progress' | 'complete' | 'failed';
+  pinned: boolean;
+  transcoding: boolean;
+  created_at: number;
+  last_accessed: number;
+};
+
+interface ICacheListResponse {
+  message: string;
+  success: boolean;
+  entries: ICacheEntry[];
+  total_size: number;
+};
+
+interface ICacheEntryResponse {
+  message: string;
+  success: boolean;
+  entry: ICacheEntry | null;
+};
+
+export const listCache = () => invoke<ICacheListResponse>('list_cache');
+
+export const getCacheEntry = (inputPath: string) => invoke<ICacheEntryResponse>('get_cache_entry', { inputPath });
+
+export const deleteCacheEntry = (inputPath: string) => invoke<ICacheEntryResponse>('delete_cache_entry', { inputPath });
+
+export const clearCache = (includePinned = false) => invoke<ICacheListResponse>('clear_cache', { includePinned });
+
+export const pinCacheEntry = (inputPath: string, pinned: boolean) => invoke<boolean>('pin_cache_entry', { inputPath, pinned });
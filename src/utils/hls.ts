@@ -1,10 +1,16 @@
 import { invoke } from '@tauri-apps/api/tauri';
+import { listen } from '@tauri-apps/api/event';
+
+import type { EventCallback } from '@tauri-apps/api/event';
 
 interface IApiResponse {
   message: string;
   success: boolean;
   playlist_url: string;
   duration: number;
+  encoder: string | null;
+  transcode_mode: string | null;
+  playback_mode: 'hls' | 'direct';
 };
 
 interface IResult {
@@ -13,7 +19,7 @@ interface IResult {
 };
 
 export const getHlsUrl = async (inputPath: string) => new Promise<IResult>(async (resolve, reject) => {
-  const response = await invoke<IApiResponse>('generate_hls', { inputPath }).catch(e => {
+  const response = await invoke<IApiResponse>('generate_hls', { inputPath, preempt: true }).catch(e => {
     throw new Error(e);
   });
   const { success, playlist_url: url, message, duration } = response;
@@ -23,4 +29,36 @@ export const getHlsUrl = async (inputPath: string) => new Promise<IResult>(async
       duration,
     });
   } else reject(message);
-});
\ No newline at end of file
+});
+
+export const cancelHls = (inputPath: string) => invoke('cancel_hls', { inputPath });
+
+export interface IHlsProgress {
+  job_id: string;
+  percent: number;
+  fps: number;
+  speed: number;
+  eta: number | null;
+  done: boolean;
+};
+
+export const listenHlsProgress = (handler: EventCallback<IHlsProgress>) => listen<IHlsProgress>('hls-progress', handler);
+
+export interface IAudioTrack {
+  index: number;
+  name: string;
+  playlist: string | null;
+  language: string | null;
+  title: string | null;
+  codec: string;
+  channels: number | null;
+  default: boolean;
+};
+
+interface IAudioTrackResponse {
+  message: string;
+  success: boolean;
+  tracks: IAudioTrack[];
+};
+
+export const listAudioTracks = (inputPath: string) => invoke<IAudioTrackResponse>('list_audio_tracks', { inputPath });
@@ -1,2 +1,3 @@
 export * from './hls';
-export * from './subtitle';
\ No newline at end of file
+export * from './subtitle';
+export * from './cache';
\ No newline at end of file
@@ -13,4 +13,24 @@ export const getSubtileUrl = async (inputPath: string) => new Promise<string>(as
   const { success, subtitle_url: url, message } = response;
   if(success && url) resolve(url);
   else reject(message);
-});
\ No newline at end of file
+});
+
+export interface ISubtitleTrack {
+  file: string;
+  url: string;
+  language: string | null;
+  title: string | null;
+  source: 'embedded' | 'sidecar' | 'asr';
+  encoding: string | null;
+};
+
+interface ISubtitleListResponse {
+  message: string;
+  success: boolean;
+  subtitles: ISubtitleTrack[];
+};
+
+export const listSubtitles = (inputPath: string) => invoke<ISubtitleListResponse>('list_subtitles', { inputPath });
+
+export const setSubtitleEncoding = (inputPath: string, file: string, encoding: string | null) =>
+  invoke<ISubtitleListResponse>('set_subtitle_encoding', { inputPath, file, encoding });